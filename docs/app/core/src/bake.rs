@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+
+use serde_derive::Serialize;
+
+use crate::chat::UNKNOWN_AUTHOR_DISTANCE;
+use crate::data::{start_text, GeneralPerson, Job, Person, Trait};
+use crate::database::Database;
+
+// traits of personas range from -TRAIT_RANGE to TRAIT_RANGE
+const TRAIT_RANGE: f32 = 10.0;
+// parameter value telling the game that a trait does not influence the line
+const IGNORED_PARAMETER: f32 = 8.0;
+
+// Category enum of the game
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Category {
+    Merchant,
+    #[allow(dead_code)]
+    Bum,
+    Politic,
+    Religious,
+    LowWorker,
+    #[allow(dead_code)]
+    HighWorker,
+    Government,
+}
+
+impl From<Job> for Category {
+    fn from(job: Job) -> Self {
+        match job {
+            Job::Farmer | Job::Fisherman | Job::Miner => Category::LowWorker,
+            Job::Merchant => Category::Merchant,
+            Job::Politician => Category::Politic,
+            Job::Noble => Category::Government,
+            Job::Priest => Category::Religious,
+        }
+    }
+}
+
+// dialogue tree in the chatDB format of the game, message 0 holds the openers
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct BakedDialogue {
+    pub(crate) messages: Vec<BakedMessage>,
+}
+
+// lines that can be said at one point of the conversation
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct BakedMessage {
+    pub(crate) id: usize,
+    pub(crate) possibilities: Vec<BakedLine>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct BakedLine {
+    // game categories of the jobs of the contributors who said the line
+    pub(crate) categories: Vec<u8>,
+    // mean character of the contributors
+    pub(crate) parameters: BakedParameters,
+    pub(crate) contents: String,
+    // message with the replies to the line, empty where the tree was pruned
+    pub(crate) options: Vec<usize>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BakedParameters {
+    rebellion: f32,
+    fear_propension: f32,
+    popularity: f32,
+    animosity: f32,
+    political_agreement: f32,
+    fear: f32,
+}
+
+impl BakedParameters {
+    // constant traits of the game range from 0 to 1 and the ones depending on
+    // the player from -1 to 1, traits the contributors disagree on are ignored
+    fn new(contributors: &[GeneralPerson]) -> Self {
+        let value = |character_trait| {
+            let values: Vec<f32> = contributors
+                .iter()
+                .map(|contributor| contributor.trait_value(character_trait) as f32)
+                .collect();
+            if values.iter().any(|&value| value > 0.0) && values.iter().any(|&value| value < 0.0)
+            {
+                return IGNORED_PARAMETER;
+            }
+
+            let mean = values.iter().sum::<f32>() / values.len() as f32 / TRAIT_RANGE;
+            match character_trait {
+                Trait::Rebellion | Trait::FearPropension | Trait::Popularity => {
+                    ((mean + 1.0) / 2.0).clamp(0.0, 1.0)
+                }
+                Trait::Animosity | Trait::PoliticalAgreement | Trait::Fear => mean.clamp(-1.0, 1.0),
+            }
+        };
+        BakedParameters {
+            rebellion: value(Trait::Rebellion),
+            fear_propension: value(Trait::FearPropension),
+            popularity: value(Trait::Popularity),
+            animosity: value(Trait::Animosity),
+            political_agreement: value(Trait::PoliticalAgreement),
+            fear: value(Trait::Fear),
+        }
+    }
+}
+
+// dialogue of the npc opened from the start phrase of the context, keeping the breadth
+// most likely lines at every point and at most depth lines in a row;
+// a branch never returns to a phrase it has already visited
+pub(crate) fn bake(
+    database: &Database,
+    person: &GeneralPerson,
+    context: &str,
+    depth: usize,
+    breadth: usize,
+) -> BakedDialogue {
+    let mut messages = Vec::new();
+    if let Some(start) = database.get_index(&start_text(context)) {
+        expand(
+            database,
+            person,
+            &mut vec![start],
+            depth,
+            breadth,
+            &mut messages,
+        );
+    }
+    BakedDialogue { messages }
+}
+
+// json array with a dialogue tree in the chatDB format for every npc
+// of the json array of person descriptions
+pub fn bake_all(
+    database: &Database,
+    persons_description: &str,
+    you_talk: bool,
+    context: &str,
+    depth: usize,
+    breadth: usize,
+) -> Option<String> {
+    let persons: Vec<Person> = serde_json::from_str(persons_description).ok()?;
+    let dialogues: Vec<BakedDialogue> = persons
+        .into_iter()
+        .map(|person| {
+            bake(
+                database,
+                &GeneralPerson::new(person, you_talk),
+                context,
+                depth,
+                breadth,
+            )
+        })
+        .collect();
+    serde_json::to_string(&dialogues).ok()
+}
+
+// adds the message answering the last phrase of the branch, returns its id
+fn expand(
+    database: &Database,
+    speaker: &GeneralPerson,
+    branch: &mut Vec<usize>,
+    depth: usize,
+    breadth: usize,
+    messages: &mut Vec<BakedMessage>,
+) -> Option<usize> {
+    if depth == 0 {
+        return None;
+    }
+
+    let responses = top_responses(database, speaker, branch, breadth);
+    if responses.is_empty() {
+        return None;
+    }
+
+    let id = messages.len();
+    messages.push(BakedMessage {
+        id,
+        possibilities: Vec::new(),
+    });
+
+    let mut listener = speaker.clone();
+    listener.youtalk = !speaker.youtalk;
+    for (response, contributors) in responses {
+        let options = if database.phrases[response].ending.is_some() {
+            None
+        } else {
+            branch.push(response);
+            let options = expand(database, &listener, branch, depth - 1, breadth, messages);
+            branch.pop();
+            options
+        };
+
+        let mut categories: Vec<u8> = contributors
+            .iter()
+            .map(|contributor| Category::from(contributor.job()) as u8)
+            .collect();
+        categories.sort();
+        categories.dedup();
+
+        messages[id].possibilities.push(BakedLine {
+            categories,
+            parameters: BakedParameters::new(&contributors),
+            contents: closest_text(database, response, speaker),
+            options: options.into_iter().collect(),
+        });
+    }
+
+    Some(id)
+}
+
+// responses to the last phrase of the branch with the highest exp(-distance) weight,
+// together with their contributors; the game format has no conditions,
+// so responses restricted by one are left out
+fn top_responses(
+    database: &Database,
+    speaker: &GeneralPerson,
+    branch: &[usize],
+    breadth: usize,
+) -> Vec<(usize, Vec<GeneralPerson>)> {
+    let phrase = &database.phrases[*branch.last().unwrap()];
+    let mut responses: BTreeMap<usize, (f32, Vec<GeneralPerson>)> = BTreeMap::new();
+    for response in &phrase.responses {
+        if branch.contains(&response.index) || phrase.is_conditional(response.index) {
+            continue;
+        }
+        let entry = responses.entry(response.index).or_default();
+        entry.0 += response.count as f32 * f32::exp(-response.person.distance(speaker));
+        if !entry.1.contains(&response.person) {
+            entry.1.push(response.person.clone());
+        }
+    }
+
+    let mut responses: Vec<(usize, (f32, Vec<GeneralPerson>))> = responses.into_iter().collect();
+    responses.sort_by(|lhs, rhs| rhs.1 .0.total_cmp(&lhs.1 .0));
+    responses.truncate(breadth);
+    responses
+        .into_iter()
+        .map(|(response, (_, contributors))| (response, contributors))
+        .collect()
+}
+
+// text variant whose author is the closest to the speaker
+fn closest_text(database: &Database, index: usize, speaker: &GeneralPerson) -> String {
+    let phrase = &database.phrases[index];
+    let distance = |position: &usize| {
+        phrase
+            .author(*position)
+            .map_or(UNKNOWN_AUTHOR_DISTANCE, |author| author.distance(speaker))
+    };
+    let position = (0..phrase.texts.len())
+        .min_by(|lhs, rhs| distance(lhs).total_cmp(&distance(rhs)))
+        .unwrap_or(0);
+    phrase.texts[position].clone()
+}
@@ -1,96 +1,834 @@
-use rand::Rng;
-use rand::{rngs::ThreadRng, thread_rng};
-use std::collections::HashMap;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::zip;
 
-use crate::data::GeneralPerson;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::data::{
+    ending_text, is_ending_text, start_text, GeneralPerson, HistoryResponse, Person, Phrase,
+    Response, Situation,
+};
 use crate::database::Database;
+use crate::template::{fill, templatize};
+use crate::transcript::{Transcript, Turn, TurnKind};
 
 const CHAT_VARIANTS: usize = 4;
+// fewer responses than this for a history make Chat back off to a shorter one
+const HISTORY_MIN_RESPONSES: usize = 2;
+// minimal word overlap for a phrase to lend its responses to a dead end
+const SIMILARITY_THRESHOLD: f32 = 0.5;
+// extra weight of a response recorded in exactly the current situation
+const SITUATION_WEIGHT: f32 = 2.0;
+// distance assumed for text variants without a known author
+pub(crate) const UNKNOWN_AUTHOR_DISTANCE: f32 = 1.0;
+// default preference for variants in the voice the speaker already used
+const VOICE_STRENGTH: f32 = 1.0;
+// default pull of goal phrases on the options leading to them
+const GOAL_STRENGTH: f32 = 1.0;
+// default weight loss of options said during the last REPETITION_WINDOW turns,
+// repetition is allowed unless a caller opts in
+const REPETITION_PENALTY: f32 = 0.0;
+const REPETITION_WINDOW: usize = 6;
+
+// response, persona that gave it, the weight of the situation it was recorded in
+// and the number of times it was given
+type Options = Vec<(usize, GeneralPerson, f32, u32)>;
+
+// where Chat takes options from when the current phrase has no responses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fallback {
+    // responses of the phrase with the most similar words
+    Similar,
+    // openers of the start phrase
+    Restart,
+    // phrases marked as generic continuations
+    Generic,
+}
+
+// response option offered by Chat together with the data it was sampled from
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Suggestion {
+    pub(crate) index: usize,
+    pub(crate) text: String,
+    // share of exp(-distance) weight, scaled by situation match,
+    // among all responses to the current phrase
+    pub(crate) probability: f32,
+    pub(crate) contributors: usize,
+    pub(crate) personas: Vec<GeneralPerson>,
+    // outcome if choosing this option ends the conversation
+    pub(crate) ending: Option<String>,
+    // set when the current phrase had no responses of its own
+    pub(crate) fallback: Option<Fallback>,
+}
+
+impl Suggestion {
+    fn new(index: usize) -> Self {
+        Suggestion {
+            index,
+            text: String::new(),
+            probability: 0.0,
+            contributors: 0,
+            personas: Vec::new(),
+            ending: None,
+            fallback: None,
+        }
+    }
+}
+
+// serializable snapshot of a Chat, phrases are stored by text so that
+// the state stays valid against a reloaded copy of the database
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ChatState {
+    query: Option<String>,
+    person: GeneralPerson,
+    transcript: Transcript,
+    history_length: usize,
+    fallbacks: Vec<Fallback>,
+    #[serde(default)]
+    participants: Vec<GeneralPerson>,
+    #[serde(default)]
+    flags: BTreeSet<String>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    #[serde(default)]
+    context: String,
+    #[serde(default)]
+    situation: Situation,
+    #[serde(default)]
+    situation_filter: bool,
+    #[serde(default = "default_voice_strength")]
+    voice_strength: f32,
+    #[serde(default)]
+    goals: Vec<String>,
+    #[serde(default = "default_goal_strength")]
+    goal_strength: f32,
+    #[serde(default = "default_repetition")]
+    repetition: (f32, usize),
+    seed: [u8; 32],
+    stream: u64,
+    word_pos: u128,
+}
 
 pub struct Chat {
     database: *mut Database,
-    gen: ThreadRng,
+    gen: ChaCha8Rng,
     query_options: Vec<usize>,
+    // offered text variants together with their authors
+    query_variants: Vec<(String, Option<GeneralPerson>)>,
     query: Option<usize>,
     person: GeneralPerson,
+    transcript: Transcript,
+    // number of last phrases responses are conditioned on
+    history_length: usize,
+    // tried in order when the current phrase is a dead end
+    fallbacks: Vec<Fallback>,
+    fallback: Option<Fallback>,
+    // speakers of a multi-party conversation, empty when two people alternate
+    participants: Vec<GeneralPerson>,
+    // context flags checked by response conditions
+    flags: BTreeSet<String>,
+    // values of placeholders in phrase texts
+    variables: BTreeMap<String, String>,
+    // situation the conversation started in
+    context: String,
+    // recorded with every response and used to weight options
+    situation: Situation,
+    // keeps only responses recorded in a matching situation
+    situation_filter: bool,
+    // how strongly variants keep to the authors a speaker already used
+    voice_strength: f32,
+    // texts of phrases the conversation is steered toward until they are said
+    goals: Vec<String>,
+    goal_strength: f32,
+    // distances to every goal phrase, valid for the given database revision
+    goal_distances: (u64, BTreeMap<usize, Vec<Option<usize>>>),
+    // penalty against phrases said recently and the number of turns it looks back
+    repetition: (f32, usize),
+}
+
+// weight of a response recorded in the given situation,
+// strict filtering drops it unless the situation matches fully
+fn situation_weight(situation: &Situation, recorded: &Situation, strict: bool) -> Option<f32> {
+    let similarity = situation.similarity(recorded);
+    if strict && similarity < 1.0 {
+        None
+    } else {
+        Some(1.0 + SITUATION_WEIGHT * similarity)
+    }
+}
+
+fn default_voice_strength() -> f32 {
+    VOICE_STRENGTH
+}
+
+fn default_goal_strength() -> f32 {
+    GOAL_STRENGTH
+}
+
+fn default_repetition() -> (f32, usize) {
+    (REPETITION_PENALTY, REPETITION_WINDOW)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Participant {
+    name: String,
+    you_talk: bool,
+    person: Person,
 }
 
 impl Chat {
     pub fn new(database: &mut Database, you_talk: bool, person_descrirption: &str) -> Self {
-        Chat {
+        Chat::with_person(
             database,
-            gen: thread_rng(),
-            query_options: Vec::new(),
-            query: None,
-            person: GeneralPerson::new(
-                serde_json::from_str(person_descrirption).unwrap(),
-                you_talk,
-            ),
+            GeneralPerson::new(serde_json::from_str(person_descrirption).unwrap(), you_talk),
+        )
+    }
+
+    // participants are described by a json array of {"name", "you_talk", "person"} objects,
+    // the first participant speaks first
+    pub fn new_party(database: &mut Database, participants_description: &str) -> Option<Self> {
+        let participants: Vec<Participant> = serde_json::from_str(participants_description).ok()?;
+        let participants: Vec<GeneralPerson> = participants
+            .into_iter()
+            .map(|participant| {
+                GeneralPerson::with_role(participant.person, participant.you_talk, &participant.name)
+            })
+            .collect();
+
+        let mut chat = Chat::with_person(database, participants.first()?.clone());
+        chat.participants = participants;
+        Some(chat)
+    }
+
+    // current npc persona in the person description format, effects of the
+    // conversation included
+    pub fn person_description(&self) -> String {
+        serde_json::to_string(self.person.person()).unwrap_or_default()
+    }
+
+    pub fn participants_description(&self) -> String {
+        let participants: Vec<Participant> = self
+            .participants
+            .iter()
+            .map(|participant| Participant {
+                name: participant.role.clone().unwrap_or_default(),
+                you_talk: participant.youtalk,
+                person: *participant.person(),
+            })
+            .collect();
+        serde_json::to_string(&participants).unwrap_or_default()
+    }
+
+    // picks who says the next phrase of a multi-party conversation
+    pub fn choose_speaker(&mut self, name: &str) -> bool {
+        if let Some(participant) = self
+            .participants
+            .iter()
+            .find(|participant| participant.role.as_deref() == Some(name))
+        {
+            self.person = participant.clone();
+            true
+        } else {
+            false
+        }
+    }
+
+    // makes sampling reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.gen = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    // context names the situation (e.g. "tavern", "market at night"),
+    // empty context starts from the general start phrase
+    pub fn start(&mut self, context: &str) {
+        let text = start_text(context);
+        self.enter(context);
+        self.get_database().insert_texts_at(&text, vec![text.clone()]);
+    }
+
+    // starts in the context without adding its start phrase to the database
+    pub(crate) fn enter(&mut self, context: &str) {
+        self.context = context.to_string();
+    }
+
+    // situation is a json object with optional "location", "time", "scenario",
+    // "tags" and any other string fields, fails on a malformed description
+    pub fn set_situation(&mut self, situation_description: &str) -> bool {
+        if let Ok(situation) = serde_json::from_str(situation_description) {
+            self.situation = situation;
+            true
+        } else {
+            false
+        }
+    }
+
+    // strict filtering drops responses recorded elsewhere instead of
+    // only preferring the ones recorded in the current situation
+    pub fn set_situation_filter(&mut self, strict: bool) {
+        self.situation_filter = strict;
+    }
+
+    // 0 lets every turn draw its variant independently of the earlier ones
+    pub fn set_voice_strength(&mut self, strength: f32) {
+        self.voice_strength = strength.max(0.0);
+    }
+
+    // goals are passed as a json array of phrase texts, an empty array stops steering,
+    // fails if some goal is not a known phrase
+    pub fn set_goals(&mut self, goals_description: &str) -> bool {
+        let Ok(goals) = serde_json::from_str::<Vec<String>>(goals_description) else {
+            return false;
+        };
+        if goals
+            .iter()
+            .any(|goal| self.get_database().get_index(goal).is_none())
+        {
+            return false;
+        }
+
+        self.goals = goals;
+        true
+    }
+
+    // options one response further from a goal get exp(-strength) times less weight
+    pub fn set_goal_strength(&mut self, strength: f32) {
+        self.goal_strength = strength.max(0.0);
+    }
+
+    // options said during the last window turns get exp(-penalty) times less weight,
+    // 0 penalty allows free repetition
+    pub fn set_repetition_penalty(&mut self, penalty: f32, window: usize) {
+        self.repetition = (penalty.max(0.0), window);
+    }
+
+    // 1 keeps responses conditioned only on the last phrase
+    pub fn set_history_length(&mut self, length: usize) {
+        self.history_length = length.max(1);
+    }
+
+    pub(crate) fn set_fallbacks(&mut self, fallbacks: Vec<Fallback>) {
+        self.fallbacks = fallbacks;
+    }
+
+    pub fn set_flag(&mut self, flag: &str, value: bool) {
+        if value {
+            self.flags.insert(flag.to_string());
+        } else {
+            self.flags.remove(flag);
         }
     }
 
-    pub fn start(&mut self) {
-        self.get_database().insert_texts_at("", vec!["".to_string()]);
+    // value shown in place of {name} in phrase texts
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.to_string(), value.to_string());
+    }
+
+    // fallback used by the last call to get_suggestions
+    pub(crate) fn fallback(&self) -> Option<Fallback> {
+        self.fallback
     }
 
     pub fn get_phrases(&mut self) -> Vec<String> {
-        if let Some(index) = self.query.or_else(|| self.get_database().get_start_index()) {
-            let options = self.get_database().phrases[index].responses.clone();
+        self.get_suggestions()
+            .into_iter()
+            .map(|suggestion| suggestion.text)
+            .collect()
+    }
+
+    pub(crate) fn get_suggestions(&mut self) -> Vec<Suggestion> {
+        self.fallback = None;
+        if self.outcome().is_some() {
+            self.query_options.clear();
+            self.query_variants.clear();
+            return Vec::new();
+        }
 
+        if let Some(index) = self.query.or_else(|| self.start_index()) {
+            let mut options = self.get_options(index);
+            let mut source = index;
+            if options.is_empty() && self.query.is_none() {
+                // new contexts open with lines of the general start phrase
+                if let Some(start) = self
+                    .get_database()
+                    .get_start_index()
+                    .filter(|&start| start != index)
+                {
+                    options = self.get_options(start);
+                    source = start;
+                }
+            }
+            let mut options = self.filter_conditions(source, options);
+            if options.is_empty() {
+                for fallback in self.fallbacks.clone() {
+                    options = self.fallback_options(fallback, index);
+                    if !options.is_empty() {
+                        self.fallback = Some(fallback);
+                        break;
+                    }
+                }
+            }
+
+            let steering = self.goal_steering();
+            let (penalty, window) = self.repetition;
+            let turns = &self.transcript.turns;
+            let recent: BTreeSet<usize> = turns[turns.len().saturating_sub(window)..]
+                .iter()
+                .map(|turn| turn.index)
+                .collect();
             let probability: Vec<f32> = options
                 .iter()
-                .map(|person| f32::exp(-person.1.distance(&self.person)))
+                .map(|option| {
+                    let repeated = recent.contains(&option.0) as u8 as f32;
+                    f32::exp(-option.1.distance(&self.person) - penalty * repeated)
+                        * option.2
+                        * option.3 as f32
+                        * steering(option.0)
+                })
                 .collect();
-            let queries = self.sample_queries(options, probability);
+            let mut suggestions = self.sample_queries(options, probability);
 
-            let text_options = queries
+            self.query_variants.clear();
+            for suggestion in &mut suggestions {
+                let (text, author) = self.choose_random_phrase(suggestion.index);
+                suggestion.text = fill(&text, &self.variables);
+                self.query_variants.push((text, author));
+                suggestion.ending = self.get_database().phrases[suggestion.index]
+                    .ending
+                    .clone();
+                suggestion.fallback = self.fallback;
+            }
+            self.query_options = suggestions
                 .iter()
-                .map(|query| self.choose_random_phrase(*query))
+                .map(|suggestion| suggestion.index)
                 .collect();
-            self.query_options = queries;
-            text_options
+            suggestions
         } else {
             Vec::new()
         }
     }
 
     pub fn add_phrase(&mut self, text: &str) {
+        if is_ending_text(text) {
+            return;
+        }
+
+        let text = templatize(text, &self.variables);
+        if let Some(phrase_index) = self
+            .get_database()
+            .insert_texts_at(&text, vec![text.clone()])
+        {
+            let author = self.person.clone();
+            self.get_database().credit_last_text(phrase_index, author);
+            self.add_response(phrase_index);
+            self.record_turn(phrase_index, text, TurnKind::Typed, true);
+            self.finish_turn(phrase_index);
+        }
+    }
+
+    // records leaving the conversation as a response to the current phrase
+    pub fn end_conversation(&mut self, outcome: &str) {
+        let text = ending_text(outcome);
         if let Some(phrase_index) = self
             .get_database()
-            .insert_texts_at(text, vec![text.to_string()])
+            .insert_texts_at(&text, vec![text.clone()])
         {
+            self.get_database().mark_ending(&text, outcome);
             self.add_response(phrase_index);
+            self.record_turn(phrase_index, text, TurnKind::Ended, true);
             self.finish_turn(phrase_index);
         }
     }
 
+    // outcome label once the conversation has reached an ending phrase
+    pub fn outcome(&mut self) -> Option<String> {
+        let index = self.query?;
+        self.get_database().phrases[index].ending.clone()
+    }
+
     pub fn choose_phrase(&mut self, option_number: usize) {
         let response_index = self.query_options[option_number];
         self.add_response(response_index);
+        self.record_option(option_number, true);
         self.finish_turn(response_index);
     }
 
     pub fn choose_phrase_immutably(&mut self, option_number: usize) {
+        self.record_option(option_number, false);
         self.finish_turn(self.query_options[option_number]);
     }
+
+    // rewinds the last turn, returns whether its response was removed from the database
+    pub fn undo(&mut self) -> bool {
+        if let Some(turn) = self.transcript.turns.pop() {
+            self.query = self.transcript.turns.last().map(|turn| turn.index);
+            self.query_options.clear();
+            self.query_variants.clear();
+            self.revert_effects(&turn);
+            self.rewind_speaker(&turn);
+
+            if !turn.recorded {
+                return false;
+            }
+
+            let previous_index = self.query.unwrap_or(self.start_index().unwrap());
+            let response = Response::new(turn.index, self.person.clone(), self.situation.clone());
+            if let Some(history) = self.history_response(turn.index) {
+                self.get_database()
+                    .retract_history_from(previous_index, &history);
+            }
+            let retracted = self
+                .get_database()
+                .retract_response_from(previous_index, &response);
+
+            if retracted && turn.kind != TurnKind::Chosen {
+                self.get_database()
+                    .retract_text_at(turn.index, turn.stored_text());
+            }
+            retracted
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    pub(crate) fn state(&mut self) -> ChatState {
+        let query = self
+            .query
+            .map(|index| self.get_database().phrases[index].texts[0].clone());
+        ChatState {
+            query,
+            person: self.person.clone(),
+            transcript: self.transcript.clone(),
+            history_length: self.history_length,
+            fallbacks: self.fallbacks.clone(),
+            participants: self.participants.clone(),
+            flags: self.flags.clone(),
+            variables: self.variables.clone(),
+            context: self.context.clone(),
+            situation: self.situation.clone(),
+            situation_filter: self.situation_filter,
+            voice_strength: self.voice_strength,
+            goals: self.goals.clone(),
+            goal_strength: self.goal_strength,
+            repetition: self.repetition,
+            seed: self.gen.get_seed(),
+            stream: self.gen.get_stream(),
+            word_pos: self.gen.get_word_pos(),
+        }
+    }
+
+    // fails if some phrase of the state is missing from the database
+    pub(crate) fn restore(database: &mut Database, state: ChatState) -> Option<Self> {
+        let query = match &state.query {
+            Some(text) => Some(database.get_index(text)?),
+            None => None,
+        };
+
+        let mut transcript = state.transcript;
+        for turn in &mut transcript.turns {
+            turn.index = database.get_index(turn.stored_text())?;
+        }
+
+        let mut gen = ChaCha8Rng::from_seed(state.seed);
+        gen.set_stream(state.stream);
+        gen.set_word_pos(state.word_pos);
+
+        Some(Chat {
+            database,
+            gen,
+            query_options: Vec::new(),
+            query_variants: Vec::new(),
+            query,
+            person: state.person,
+            transcript,
+            history_length: state.history_length,
+            fallbacks: state.fallbacks,
+            fallback: None,
+            participants: state.participants,
+            flags: state.flags,
+            variables: state.variables,
+            context: state.context,
+            situation: state.situation,
+            situation_filter: state.situation_filter,
+            voice_strength: state.voice_strength,
+            goals: state.goals,
+            goal_strength: state.goal_strength,
+            goal_distances: (0, BTreeMap::new()),
+            repetition: state.repetition,
+        })
+    }
 }
 
 impl Chat {
+    fn with_person(database: &mut Database, person: GeneralPerson) -> Self {
+        Chat {
+            database,
+            gen: ChaCha8Rng::from_entropy(),
+            query_options: Vec::new(),
+            query_variants: Vec::new(),
+            query: None,
+            person,
+            transcript: Transcript::new(),
+            history_length: 1,
+            fallbacks: Vec::new(),
+            fallback: None,
+            participants: Vec::new(),
+            flags: BTreeSet::new(),
+            variables: BTreeMap::new(),
+            context: String::new(),
+            situation: Situation::default(),
+            situation_filter: false,
+            voice_strength: VOICE_STRENGTH,
+            goals: Vec::new(),
+            goal_strength: GOAL_STRENGTH,
+            goal_distances: (0, BTreeMap::new()),
+            repetition: default_repetition(),
+        }
+    }
+
     fn get_database(&mut self) -> &mut Database {
         unsafe { &mut (*self.database) }
     }
 
+    fn start_index(&mut self) -> Option<usize> {
+        let text = start_text(&self.context);
+        self.get_database().get_index(&text)
+    }
+
     fn add_response(&mut self, response_index: usize) {
-        let previous_index = self.query.unwrap_or(self.get_database().get_start_index().unwrap());
-        let person = self.person;
+        let previous_index = self.query.unwrap_or(self.start_index().unwrap());
+        let response = Response::new(response_index, self.person.clone(), self.situation.clone());
         self.get_database()
-            .insert_responses_to(previous_index, vec![(response_index, person)]);
+            .insert_responses_to(previous_index, vec![response]);
+
+        if let Some(history) = self.history_response(response_index) {
+            self.get_database()
+                .insert_histories_to(previous_index, vec![history]);
+        }
+    }
+
+    // phrases said before the current query, at most history_length - 1 of them
+    fn history(&self) -> Vec<usize> {
+        let turns = &self.transcript.turns;
+        let before = turns.len().saturating_sub(1);
+        let start = before.saturating_sub(self.history_length - 1);
+        turns[start..before].iter().map(|turn| turn.index).collect()
+    }
+
+    fn history_response(&self, response_index: usize) -> Option<HistoryResponse> {
+        let history = self.history();
+        if history.is_empty() {
+            None
+        } else {
+            Some(HistoryResponse::new(
+                history,
+                response_index,
+                self.person.clone(),
+                self.situation.clone(),
+            ))
+        }
+    }
+
+    // responses of the longest matching history with enough data,
+    // falling back to all responses of the phrase
+    fn get_options(&mut self, index: usize) -> Options {
+        let history = self.history();
+        let situation = self.situation.clone();
+        let strict = self.situation_filter;
+        let phrase = &self.get_database().phrases[index];
+
+        for length in (1..=history.len()).rev() {
+            let context = &history[history.len() - length..];
+            let options: Options = phrase
+                .history_responses
+                .iter()
+                .filter(|response| response.history.ends_with(context))
+                .filter_map(|response| {
+                    situation_weight(&situation, &response.situation, strict)
+                        .map(|weight| {
+                            (response.response, response.person.clone(), weight, response.count)
+                        })
+                })
+                .collect();
+
+            let choices: u32 = options.iter().map(|option| option.3).sum();
+            if choices as usize >= HISTORY_MIN_RESPONSES {
+                return options;
+            }
+        }
+
+        self.situated_options(index)
+    }
+
+    // responses of the phrase weighted by how well their situation
+    // matches the current one
+    fn situated_options(&mut self, index: usize) -> Options {
+        let situation = self.situation.clone();
+        let strict = self.situation_filter;
+        let phrase: &Phrase = &self.get_database().phrases[index];
+
+        phrase
+            .responses
+            .iter()
+            .filter_map(|response| {
+                situation_weight(&situation, &response.situation, strict).map(|weight| {
+                    (response.index, response.person.clone(), weight, response.count)
+                })
+            })
+            .collect()
+    }
+
+    fn record_option(&mut self, option_number: usize, recorded: bool) {
+        let (text, author) = self.query_variants[option_number].clone();
+        self.record_turn(
+            self.query_options[option_number],
+            text,
+            TurnKind::Chosen,
+            recorded,
+        );
+        if let Some(turn) = self.transcript.turns.last_mut() {
+            turn.author = author;
+        }
+    }
+
+    fn record_turn(&mut self, index: usize, text: String, kind: TurnKind, recorded: bool) {
+        let shown = fill(&text, &self.variables);
+        self.transcript.push(Turn {
+            index,
+            template: (shown != text).then_some(text),
+            text: shown,
+            youtalk: self.person.youtalk,
+            speaker: self.person.role.clone(),
+            kind,
+            recorded,
+            author: None,
+            before_effects: None,
+        });
     }
 
     fn finish_turn(&mut self, response_index: usize) {
         self.query = Some(response_index);
-        self.person.youtalk = !self.person.youtalk;
+        self.apply_effects(response_index);
+        if self.participants.is_empty() {
+            self.person.youtalk = !self.person.youtalk;
+        }
+    }
+
+    // effects change the npc of a two-person chat or every npc of a party,
+    // the personas before the change are kept on the last turn for undo
+    fn apply_effects(&mut self, index: usize) {
+        let effects = self.get_database().phrases[index]
+            .effects
+            .clone()
+            .unwrap_or_default();
+        if effects.is_empty() {
+            return;
+        }
+
+        let before = if self.participants.is_empty() {
+            vec![self.person.clone()]
+        } else {
+            self.participants.clone()
+        };
+        if let Some(turn) = self.transcript.turns.last_mut() {
+            turn.before_effects = Some(before);
+        }
+
+        if self.participants.is_empty() {
+            self.person.apply(&effects);
+        } else {
+            for participant in &mut self.participants {
+                if !participant.youtalk {
+                    participant.apply(&effects);
+                }
+            }
+            if let Some(speaker) = self.person.role.clone() {
+                self.choose_speaker(&speaker);
+            }
+        }
+    }
+
+    fn revert_effects(&mut self, turn: &Turn) {
+        if let Some(before) = turn.before_effects.clone() {
+            if self.participants.is_empty() {
+                self.person = before.into_iter().next().unwrap_or(self.person.clone());
+            } else {
+                self.participants = before;
+            }
+        }
+    }
+
+    fn rewind_speaker(&mut self, turn: &Turn) {
+        if let Some(speaker) = &turn.speaker {
+            self.choose_speaker(speaker);
+        } else {
+            self.person.youtalk = turn.youtalk;
+        }
+    }
+
+    // drops responses whose condition does not hold for the current speaker
+    fn filter_conditions(&mut self, index: usize, options: Options) -> Options {
+        let person = self.person.clone();
+        let flags = self.flags.clone();
+        let phrase = &self.get_database().phrases[index];
+
+        options
+            .into_iter()
+            .filter(|(response, _, _, _)| phrase.allows(*response, &person, &flags))
+            .collect()
+    }
+
+    // options are filtered by the conditions of the phrase they are borrowed from
+    fn fallback_options(&mut self, fallback: Fallback, index: usize) -> Options {
+        let start_index = self.start_index();
+
+        match fallback {
+            Fallback::Similar => {
+                match self.get_database().most_similar(index, SIMILARITY_THRESHOLD) {
+                    Some(similar) => {
+                        let options = self.situated_options(similar);
+                        self.filter_conditions(similar, options)
+                    }
+                    None => Vec::new(),
+                }
+            }
+            Fallback::Restart => match start_index.filter(|&start| start != index) {
+                Some(start) => {
+                    let options = self.situated_options(start);
+                    self.filter_conditions(start, options)
+                }
+                None => Vec::new(),
+            },
+            // generic phrases are offered by the personas who said them
+            Fallback::Generic => {
+                let person = &self.person.clone();
+                let flags = &self.flags.clone();
+                let database: &Database = self.get_database();
+                database
+                    .generic_indices()
+                    .into_iter()
+                    .filter(|&generic| generic != index)
+                    .flat_map(|generic| {
+                        database
+                            .preceding(generic)
+                            .keys()
+                            .map(|&previous| &database.phrases[previous])
+                            .filter(move |phrase| phrase.allows(generic, person, flags))
+                            .flat_map(move |phrase| {
+                                phrase
+                                    .responses
+                                    .iter()
+                                    .filter(move |response| response.index == generic)
+                            })
+                    })
+                    .map(|response| (response.index, response.person.clone(), 1.0, response.count))
+                    .collect()
+            }
+        }
     }
 
     fn sample(&mut self, options: &mut Vec<usize>, proba: &mut Vec<f32>) -> Option<usize> {
@@ -110,7 +848,7 @@ impl Chat {
             let p = self.gen.gen_range(0.0..1.0f32);
             let index = cumulative
                 .binary_search_by(|x| f32::total_cmp(x, &p))
-                .map_or_else(|x| x, |x| x);
+                .unwrap_or_else(|x| x);
 
             let option = options.swap_remove(index);
             proba.swap_remove(index);
@@ -121,27 +859,31 @@ impl Chat {
         }
     }
 
-    fn sample_queries(
-        &mut self,
-        options: Vec<(usize, GeneralPerson)>,
-        probability: Vec<f32>,
-    ) -> Vec<usize> {
-        let mut options_map = HashMap::new();
+    fn sample_queries(&mut self, options: Options, probability: Vec<f32>) -> Vec<Suggestion> {
+        let total: f32 = probability.iter().sum();
+        let mut options_map = BTreeMap::new();
         for (option, proba) in zip(options, probability) {
-            *options_map.entry(option.0).or_insert(0.0) += proba;
+            let suggestion = options_map
+                .entry(option.0)
+                .or_insert_with(|| Suggestion::new(option.0));
+            suggestion.probability += proba / total;
+            suggestion.contributors += option.3 as usize;
+            if !suggestion.personas.contains(&option.1) {
+                suggestion.personas.push(option.1);
+            }
         }
 
         let mut unique_option = Vec::new();
         let mut unique_proba = Vec::new();
 
-        for (option, proba) in options_map {
+        for (&option, suggestion) in &options_map {
             unique_option.push(option);
-            unique_proba.push(proba);
+            unique_proba.push(suggestion.probability);
         }
 
         let mut queries = Vec::new();
         while let Some(option) = self.sample(&mut unique_option, &mut unique_proba) {
-            queries.push(option);
+            queries.push(options_map.remove(&option).unwrap());
             if queries.len() == CHAT_VARIANTS {
                 break;
             }
@@ -150,9 +892,88 @@ impl Chat {
         queries
     }
 
-    fn choose_random_phrase(&mut self, query_index: usize) -> String {
-        let text_number = self.get_database().phrases[query_index].texts.len();
-        let index = self.gen.gen_range(0..text_number);
-        self.get_database().phrases[query_index].texts[index].clone()
+    // weight factor of an option by its distance to the closest goal not said yet,
+    // options that cannot lead to a goal count as one response further than the farthest
+    fn goal_steering(&mut self) -> impl Fn(usize) -> f32 {
+        let said: BTreeSet<usize> = self.transcript.turns.iter().map(|turn| turn.index).collect();
+        let strength = self.goal_strength;
+        let goals = self.goals.clone();
+        // the cache is taken out while the database is borrowed
+        let (revision, mut cached) = std::mem::take(&mut self.goal_distances);
+        let database: &Database = self.get_database();
+        let goals: Vec<usize> = goals
+            .iter()
+            .filter_map(|goal| database.get_index(goal))
+            .filter(|goal| !said.contains(goal))
+            .collect();
+
+        if revision != database.revision() {
+            cached.clear();
+        }
+        for &goal in &goals {
+            cached
+                .entry(goal)
+                .or_insert_with(|| database.distances_to(&[goal]));
+        }
+        let distances: Vec<Option<usize>> = if goals.is_empty() {
+            Vec::new()
+        } else {
+            (0..database.phrases.len())
+                .map(|index| {
+                    goals
+                        .iter()
+                        .filter_map(|goal| cached[goal][index])
+                        .min()
+                })
+                .collect()
+        };
+        let unreachable = distances.iter().flatten().max().map_or(0, |&max| max + 1);
+        self.goal_distances = (database.revision(), cached);
+
+        move |index| {
+            distances.get(index).map_or(1.0, |distance| {
+                f32::exp(-strength * distance.unwrap_or(unreachable) as f32)
+            })
+        }
+    }
+
+    // authors of the variants the current speaker has said so far
+    fn voice(&self) -> Vec<GeneralPerson> {
+        self.transcript
+            .turns
+            .iter()
+            .filter(|turn| turn.youtalk == self.person.youtalk && turn.speaker == self.person.role)
+            .filter_map(|turn| turn.author.clone())
+            .collect()
+    }
+
+    // text variants are weighted by how close their author is to the speaker
+    // and to the authors of the speaker's earlier variants
+    fn choose_random_phrase(&mut self, query_index: usize) -> (String, Option<GeneralPerson>) {
+        let person = self.person.clone();
+        let voice = self.voice();
+        let strength = self.voice_strength;
+        let phrase = &self.get_database().phrases[query_index];
+        let mut positions: Vec<usize> = (0..phrase.texts.len()).collect();
+        let mut weights: Vec<f32> = positions
+            .iter()
+            .map(|&position| {
+                let author = phrase.author(position);
+                let distance =
+                    author.map_or(UNKNOWN_AUTHOR_DISTANCE, |author| author.distance(&person));
+                let voice_distance = voice
+                    .iter()
+                    .map(|used| {
+                        author.map_or(UNKNOWN_AUTHOR_DISTANCE, |author| author.distance(used))
+                    })
+                    .min_by(f32::total_cmp)
+                    .unwrap_or(0.0);
+                f32::exp(-distance - strength * voice_distance)
+            })
+            .collect();
+
+        let position = self.sample(&mut positions, &mut weights).unwrap_or(0);
+        let phrase = &self.get_database().phrases[query_index];
+        (phrase.texts[position].clone(), phrase.author(position).cloned())
     }
 }
@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use crate::data::{GeneralPerson, Job, Trait};
+
+// condition language for responses, e.g.
+// `job in [Noble, Priest] and popularity > 5 and not flag night`
+//
+// expression := term ("or" term)*
+// term       := factor ("and" factor)*
+// factor     := "not" factor | "(" expression ")" | "flag" name
+//             | "job" "in" "[" job ("," job)* "]" | "job" "==" job
+//             | trait operator integer
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operator {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    fn parse(token: &str) -> Option<Self> {
+        match token {
+            "<" => Some(Operator::Less),
+            "<=" => Some(Operator::LessEqual),
+            ">" => Some(Operator::Greater),
+            ">=" => Some(Operator::GreaterEqual),
+            "==" => Some(Operator::Equal),
+            "!=" => Some(Operator::NotEqual),
+            _ => None,
+        }
+    }
+
+    fn compare(self, lhs: i8, rhs: i8) -> bool {
+        match self {
+            Operator::Less => lhs < rhs,
+            Operator::LessEqual => lhs <= rhs,
+            Operator::Greater => lhs > rhs,
+            Operator::GreaterEqual => lhs >= rhs,
+            Operator::Equal => lhs == rhs,
+            Operator::NotEqual => lhs != rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Condition {
+    Compare(Trait, Operator, i8),
+    Job(Vec<Job>),
+    Flag(String),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub(crate) fn evaluate(&self, person: &GeneralPerson, flags: &BTreeSet<String>) -> bool {
+        match self {
+            Condition::Compare(character_trait, operator, value) => {
+                operator.compare(person.trait_value(*character_trait), *value)
+            }
+            Condition::Job(jobs) => jobs.contains(&person.job()),
+            Condition::Flag(flag) => flags.contains(flag),
+            Condition::Not(condition) => !condition.evaluate(person, flags),
+            Condition::And(lhs, rhs) => lhs.evaluate(person, flags) && rhs.evaluate(person, flags),
+            Condition::Or(lhs, rhs) => lhs.evaluate(person, flags) || rhs.evaluate(person, flags),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s);
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+
+        let condition = parser.expression().ok_or(())?;
+        if parser.position == tokens.len() {
+            Ok(condition)
+        } else {
+            Err(())
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "()[],".contains(c) {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if "<>=!".contains(c) {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek().filter(|&&c| "<>=!".contains(c)) {
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|&&c| c.is_alphanumeric() || c == '_' || c == '-')
+            {
+                token.push(c);
+                chars.next();
+            }
+            if token.is_empty() {
+                // unknown symbols are kept so that parsing fails on them
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+// enums are parsed by their serialized names
+fn parse_name<T: serde::de::DeserializeOwned>(token: &str) -> Option<T> {
+    serde_json::from_value(serde_json::Value::String(token.to_string())).ok()
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek()?;
+        self.position += 1;
+        Some(token)
+    }
+
+    fn expect(&mut self, token: &str) -> Option<()> {
+        (self.next()? == token).then_some(())
+    }
+
+    fn expression(&mut self) -> Option<Condition> {
+        let mut condition = self.term()?;
+        while self.peek() == Some("or") {
+            self.next();
+            condition = Condition::Or(Box::new(condition), Box::new(self.term()?));
+        }
+        Some(condition)
+    }
+
+    fn term(&mut self) -> Option<Condition> {
+        let mut condition = self.factor()?;
+        while self.peek() == Some("and") {
+            self.next();
+            condition = Condition::And(Box::new(condition), Box::new(self.factor()?));
+        }
+        Some(condition)
+    }
+
+    fn factor(&mut self) -> Option<Condition> {
+        match self.next()? {
+            "not" => Some(Condition::Not(Box::new(self.factor()?))),
+            "(" => {
+                let condition = self.expression()?;
+                self.expect(")")?;
+                Some(condition)
+            }
+            "flag" => Some(Condition::Flag(self.next()?.to_string())),
+            "job" => match self.next()? {
+                "==" => Some(Condition::Job(vec![parse_name(self.next()?)?])),
+                "in" => {
+                    self.expect("[")?;
+                    let mut jobs = vec![parse_name(self.next()?)?];
+                    while self.peek() == Some(",") {
+                        self.next();
+                        jobs.push(parse_name(self.next()?)?);
+                    }
+                    self.expect("]")?;
+                    Some(Condition::Job(jobs))
+                }
+                _ => None,
+            },
+            token => {
+                let character_trait = parse_name(token)?;
+                let operator = Operator::parse(self.next()?)?;
+                let value = self.next()?.parse().ok()?;
+                Some(Condition::Compare(character_trait, operator, value))
+            }
+        }
+    }
+}
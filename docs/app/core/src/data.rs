@@ -1,4 +1,4 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::hash::Hash;
 use std::iter::zip;
 use std::ops::Add;
@@ -7,9 +7,11 @@ use std::vec;
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::condition::Condition;
+
 #[repr(u8)]
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum Job {
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum Job {
     Farmer,
     Fisherman,
     Miner,
@@ -19,6 +21,16 @@ enum Job {
     Priest
 }
 
+pub(crate) const JOBS: [Job; 7] = [
+    Job::Farmer,
+    Job::Fisherman,
+    Job::Miner,
+    Job::Merchant,
+    Job::Politician,
+    Job::Noble,
+    Job::Priest,
+];
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct Character {
     rebellion: i8,
@@ -29,7 +41,64 @@ struct Character {
     fear: i8,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Trait {
+    Rebellion,
+    FearPropension,
+    Popularity,
+    Animosity,
+    PoliticalAgreement,
+    Fear,
+}
+
+// change of a character trait caused by saying a phrase
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Effect {
+    #[serde(rename = "trait")]
+    pub(crate) character_trait: Trait,
+    pub(crate) delta: i8,
+}
+
 impl Character {
+    fn get(mut self, character_trait: Trait) -> i8 {
+        *self.get_mut(character_trait)
+    }
+
+    fn get_mut(&mut self, character_trait: Trait) -> &mut i8 {
+        match character_trait {
+            Trait::Rebellion => &mut self.rebellion,
+            Trait::FearPropension => &mut self.fear_propension,
+            Trait::Popularity => &mut self.popularity,
+            Trait::Animosity => &mut self.animosity,
+            Trait::PoliticalAgreement => &mut self.political_agreement,
+            Trait::Fear => &mut self.fear,
+        }
+    }
+
+    fn to_array(self) -> [i8; 6] {
+        [
+            self.rebellion,
+            self.fear_propension,
+            self.popularity,
+            self.animosity,
+            self.political_agreement,
+            self.fear,
+        ]
+    }
+
+    fn from_array(values: [i8; 6]) -> Self {
+        let [rebellion, fear_propension, popularity, animosity, political_agreement, fear] = values;
+        Character {
+            rebellion,
+            fear_propension,
+            popularity,
+            animosity,
+            political_agreement,
+            fear,
+        }
+    }
+
     fn to_vec(self) -> Vec<f32> {
         vec![
             self.rebellion as f32,
@@ -48,15 +117,73 @@ pub(crate) struct Person {
     character: Character,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct GeneralPerson {
     person: Person,
     pub(crate) youtalk: bool,
+    // name of the participant in conversations with more than two people
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<String>,
 }
 
 impl GeneralPerson {
     pub(crate) fn new(person: Person, youtalk: bool) -> Self {
-        GeneralPerson { person, youtalk }
+        GeneralPerson {
+            person,
+            youtalk,
+            role: None,
+        }
+    }
+
+    pub(crate) fn with_role(person: Person, youtalk: bool, role: &str) -> Self {
+        GeneralPerson {
+            person,
+            youtalk,
+            role: Some(role.to_string()),
+        }
+    }
+
+    pub(crate) fn person(&self) -> &Person {
+        &self.person
+    }
+
+    pub(crate) fn job(&self) -> Job {
+        self.person.job
+    }
+
+    pub(crate) fn trait_value(&self, character_trait: Trait) -> i8 {
+        self.person.character.get(character_trait)
+    }
+
+    // same job, role and way of talking, whatever the character
+    pub(crate) fn same_persona(&self, other: &GeneralPerson) -> bool {
+        self.youtalk == other.youtalk && self.role == other.role && self.job() == other.job()
+    }
+
+    // character traits of count choices of the person
+    pub(crate) fn character_sums(&self, count: u32) -> [i64; 6] {
+        let count = i64::from(count);
+        self.person.character.to_array().map(|value| i64::from(value) * count)
+    }
+
+    // adds the trait sums of other choices to sums of count choices in total
+    // and moves the character to their centroid
+    fn accumulate(&mut self, sums: &mut [i64; 6], other: &[i64; 6], sign: i64, count: u32) {
+        for (sum, value) in zip(sums.iter_mut(), other) {
+            *sum += sign * value;
+        }
+        if count > 0 {
+            let count = count as f64;
+            let centroid = sums.map(|sum| (sum as f64 / count).round() as i8);
+            self.person.character = Character::from_array(centroid);
+        }
+    }
+
+    pub(crate) fn apply(&mut self, effects: &[Effect]) {
+        for effect in effects {
+            let value = self.person.character.get_mut(effect.character_trait);
+            *value = value.saturating_add(effect.delta);
+        }
     }
 
     pub(crate) fn distance(&self, other: &GeneralPerson) -> f32 {
@@ -64,13 +191,12 @@ impl GeneralPerson {
             return 2.0;
         }
         (self.person.job != other.person.job) as i32 as f32
-            + GeneralPerson::cosine_distance(&self.person.character, &self.person.character)
+            + GeneralPerson::cosine_distance(&self.person.character, &other.person.character)
     }
 
     fn scalar_product<T: Add<Output = T> + Default>(lhs: &[T], rhs: &[T]) -> T
     where
         for<'a> &'a T: std::ops::Mul<&'a T, Output = T>,
-        T: std::default::Default,
     {
         zip(lhs.iter(), rhs.iter())
             .map(|(x, y)| x * y)
@@ -80,18 +206,329 @@ impl GeneralPerson {
     fn cosine_distance(lhs: &Character, rhs: &Character) -> f32 {
         let lslice = lhs.to_vec();
         let rslice = rhs.to_vec();
-        1.0 - GeneralPerson::scalar_product(&lslice, &rslice)
-            / f32::sqrt(
-                GeneralPerson::scalar_product(&lslice, &lslice)
-                    * GeneralPerson::scalar_product(&rslice, &rslice),
-            )
+        let norm = f32::sqrt(
+            GeneralPerson::scalar_product(&lslice, &lslice)
+                * GeneralPerson::scalar_product(&rslice, &rslice),
+        );
+
+        // neutral characters are treated as unrelated to any other
+        if norm == 0.0 {
+            return 1.0;
+        }
+        1.0 - GeneralPerson::scalar_product(&lslice, &rslice) / norm
+    }
+}
+
+// circumstances a response was recorded in
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Situation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) scenario: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) tags: BTreeSet<String>,
+    // any other named circumstances
+    #[serde(flatten)]
+    pub(crate) extra: BTreeMap<String, String>,
+}
+
+impl Situation {
+    fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        [
+            ("location", &self.location),
+            ("time", &self.time),
+            ("scenario", &self.scenario),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .chain(
+            self.extra
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.fields()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fields().next().is_none() && self.tags.is_empty()
+    }
+
+    // share of fields and tags of this situation that the recorded one has too,
+    // 1.0 for an empty situation
+    pub(crate) fn similarity(&self, recorded: &Situation) -> f32 {
+        let total = self.fields().count() + self.tags.len();
+        if total == 0 {
+            return 1.0;
+        }
+
+        let matched = self
+            .fields()
+            .filter(|(name, value)| recorded.get(name) == Some(value))
+            .count()
+            + self.tags.intersection(&recorded.tags).count();
+        matched as f32 / total as f32
+    }
+}
+
+// response recorded together with the phrases said before the one it answers,
+// history is ordered from the oldest phrase, choices are aggregated like responses
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub(crate) struct HistoryResponse {
+    pub(crate) history: Vec<usize>,
+    pub(crate) response: usize,
+    pub(crate) person: GeneralPerson,
+    #[serde(default, skip_serializing_if = "Situation::is_empty")]
+    pub(crate) situation: Situation,
+    // every choice used to be stored on its own
+    #[serde(default = "single_choice")]
+    pub(crate) count: u32,
+    #[serde(default)]
+    pub(crate) traits: [i64; 6],
+}
+
+fn single_choice() -> u32 {
+    1
+}
+
+impl HistoryResponse {
+    pub(crate) fn new(
+        history: Vec<usize>,
+        response: usize,
+        person: GeneralPerson,
+        situation: Situation,
+    ) -> Self {
+        let traits = person.character_sums(1);
+        HistoryResponse {
+            history,
+            response,
+            person,
+            situation,
+            count: 1,
+            traits,
+        }
+    }
+}
+
+// number of times a response was given in the same situation by personas
+// of the same job, role and way of talking, every choice is appended to the count
+// instead of the list of responses and the person is the centroid of their characters
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+pub(crate) struct Response {
+    pub(crate) index: usize,
+    pub(crate) person: GeneralPerson,
+    #[serde(default, skip_serializing_if = "Situation::is_empty")]
+    pub(crate) situation: Situation,
+    pub(crate) count: u32,
+    // sums of the character traits of all choices, the centroid is kept exact
+    // when choices are added and retracted
+    #[serde(default)]
+    pub(crate) traits: [i64; 6],
+}
+
+impl Response {
+    pub(crate) fn new(index: usize, person: GeneralPerson, situation: Situation) -> Self {
+        let traits = person.character_sums(1);
+        Response {
+            index,
+            person,
+            situation,
+            count: 1,
+            traits,
+        }
     }
 }
 
+// choices counted together when they answer the same way in the same situation
+// and their personas only differ in character
+pub(crate) trait Choice: Clone {
+    fn same_choice(&self, other: &Self) -> bool;
+
+    fn count(&self) -> u32;
+
+    // the same choice without any choosers, to add choices to
+    fn emptied(&self) -> Self;
+
+    fn add(&mut self, other: &Self);
+
+    // takes back the choices of other, which were added before
+    fn remove(&mut self, other: &Self);
+}
+
+impl Choice for Response {
+    fn same_choice(&self, other: &Response) -> bool {
+        self.index == other.index
+            && self.situation == other.situation
+            && self.person.same_persona(&other.person)
+    }
+
+    fn count(&self) -> u32 {
+        self.count
+    }
+
+    fn emptied(&self) -> Self {
+        Response {
+            count: 0,
+            traits: [0; 6],
+            ..self.clone()
+        }
+    }
+
+    fn add(&mut self, other: &Response) {
+        self.count += other.count;
+        self.person.accumulate(&mut self.traits, &other.traits, 1, self.count);
+    }
+
+    fn remove(&mut self, other: &Response) {
+        self.count -= other.count;
+        self.person.accumulate(&mut self.traits, &other.traits, -1, self.count);
+    }
+}
+
+impl Choice for HistoryResponse {
+    fn same_choice(&self, other: &HistoryResponse) -> bool {
+        self.history == other.history
+            && self.response == other.response
+            && self.situation == other.situation
+            && self.person.same_persona(&other.person)
+    }
+
+    fn count(&self) -> u32 {
+        self.count
+    }
+
+    fn emptied(&self) -> Self {
+        HistoryResponse {
+            count: 0,
+            traits: [0; 6],
+            ..self.clone()
+        }
+    }
+
+    fn add(&mut self, other: &HistoryResponse) {
+        self.count += other.count;
+        self.person.accumulate(&mut self.traits, &other.traits, 1, self.count);
+    }
+
+    fn remove(&mut self, other: &HistoryResponse) {
+        self.count -= other.count;
+        self.person.accumulate(&mut self.traits, &other.traits, -1, self.count);
+    }
+}
+
+// responses used to be stored once per choice as (response index, persona)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredResponse {
+    Counted(Response),
+    Single(usize, GeneralPerson),
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(from = "StoredPhrase")]
 pub(crate) struct Phrase {
     pub(crate) texts: Vec<String>,
-    pub(crate) responses: Vec<(usize, GeneralPerson)>,
+    pub(crate) responses: Vec<Response>,
+    #[serde(default)]
+    pub(crate) history_responses: Vec<HistoryResponse>,
+    // outcome label of a phrase that finishes the conversation
+    #[serde(default)]
+    pub(crate) ending: Option<String>,
+    // offered as a continuation when the conversation reaches a dead end
+    #[serde(default)]
+    pub(crate) generic: bool,
+    // applied to the npc persona whenever the phrase is said,
+    // an empty list clears effects set before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) effects: Option<Vec<Effect>>,
+    // responses that can only be said when the condition holds,
+    // stored as (response index, condition source), empty source removes a condition
+    #[serde(default)]
+    pub(crate) conditions: Vec<(usize, String)>,
+    // conditions parsed once, None for a source that does not parse
+    #[serde(skip)]
+    pub(crate) parsed_conditions: Vec<(usize, Option<Condition>)>,
+    // personas of the contributors who typed text variants,
+    // stored as (position in texts, persona)
+    #[serde(default)]
+    pub(crate) authors: Vec<(usize, GeneralPerson)>,
+}
+
+// phrase as stored in either format, single responses keep their position
+// so that difference starts stay valid, equal choices are counted together on merge
+#[derive(Deserialize)]
+struct StoredPhrase {
+    texts: Vec<String>,
+    responses: Vec<StoredResponse>,
+    #[serde(default)]
+    history_responses: Vec<HistoryResponse>,
+    #[serde(default)]
+    ending: Option<String>,
+    #[serde(default)]
+    generic: bool,
+    #[serde(default)]
+    effects: Option<Vec<Effect>>,
+    #[serde(default)]
+    conditions: Vec<(usize, String)>,
+    // situations of single responses, stored as (position in responses, situation)
+    #[serde(default)]
+    situations: Vec<(usize, Situation)>,
+    #[serde(default)]
+    authors: Vec<(usize, GeneralPerson)>,
+}
+
+impl From<StoredPhrase> for Phrase {
+    fn from(stored: StoredPhrase) -> Self {
+        let mut situations: BTreeMap<usize, Situation> = stored.situations.into_iter().collect();
+        let responses = stored
+            .responses
+            .into_iter()
+            .enumerate()
+            .map(|(position, response)| match response {
+                // sums were not stored before choices of different characters
+                // were aggregated, all choices had the character of the person then
+                StoredResponse::Counted(mut response) => {
+                    if response.traits == [0; 6] {
+                        response.traits = response.person.character_sums(response.count);
+                    }
+                    response
+                }
+                StoredResponse::Single(index, person) => Response::new(
+                    index,
+                    person,
+                    situations.remove(&position).unwrap_or_default(),
+                ),
+            })
+            .collect();
+
+        let mut history_responses = stored.history_responses;
+        for response in &mut history_responses {
+            if response.traits == [0; 6] {
+                response.traits = response.person.character_sums(response.count);
+            }
+        }
+
+        let mut phrase = Phrase {
+            texts: stored.texts,
+            responses,
+            history_responses,
+            ending: stored.ending,
+            generic: stored.generic,
+            effects: stored.effects,
+            conditions: stored.conditions,
+            parsed_conditions: Vec::new(),
+            authors: stored.authors,
+        };
+        phrase.parse_conditions();
+        phrase
+    }
 }
 
 impl Phrase {
@@ -99,8 +536,96 @@ impl Phrase {
         Phrase {
             texts: Vec::new(),
             responses: Vec::new(),
+            history_responses: Vec::new(),
+            ending: None,
+            generic: false,
+            effects: None,
+            conditions: Vec::new(),
+            parsed_conditions: Vec::new(),
+            authors: Vec::new(),
+        }
+    }
+}
+
+impl Phrase {
+    pub(crate) fn author(&self, position: usize) -> Option<&GeneralPerson> {
+        self.authors
+            .iter()
+            .find(|(recorded, _)| *recorded == position)
+            .map(|(_, author)| author)
+    }
+
+    // number of times the phrase was answered
+    pub(crate) fn response_count(&self) -> usize {
+        self.responses.iter().map(|response| response.count as usize).sum()
+    }
+
+    // whether the person can answer with the response,
+    // a condition that does not parse never holds
+    pub(crate) fn allows(
+        &self,
+        response_index: usize,
+        person: &GeneralPerson,
+        flags: &BTreeSet<String>,
+    ) -> bool {
+        self.parsed_conditions
+            .iter()
+            .find(|(index, _)| *index == response_index)
+            .is_none_or(|(_, condition)| {
+                condition
+                    .as_ref()
+                    .is_some_and(|condition| condition.evaluate(person, flags))
+            })
+    }
+
+    pub(crate) fn is_conditional(&self, response_index: usize) -> bool {
+        self.parsed_conditions
+            .iter()
+            .any(|(index, _)| *index == response_index)
+    }
+
+    pub(crate) fn set_condition(&mut self, response_index: usize, condition: &str) {
+        self.conditions.retain(|(index, _)| *index != response_index);
+        self.conditions.push((response_index, condition.to_string()));
+        self.parsed_conditions.retain(|(index, _)| *index != response_index);
+        if !condition.is_empty() {
+            self.parsed_conditions
+                .push((response_index, Condition::from_str(condition).ok()));
         }
     }
+
+    // rebuilds the parsed conditions after their sources changed
+    pub(crate) fn parse_conditions(&mut self) {
+        self.parsed_conditions = self
+            .conditions
+            .iter()
+            .filter(|(_, condition)| !condition.is_empty())
+            .map(|(index, condition)| (*index, Condition::from_str(condition).ok()))
+            .collect();
+    }
+}
+
+// text of the phrase conversations in the given context start from,
+// the empty context is the general start phrase
+pub(crate) fn start_text(context: &str) -> String {
+    if context.is_empty() {
+        String::new()
+    } else {
+        format!("<start {}>", context)
+    }
+}
+
+pub(crate) fn is_start_text(text: &str) -> bool {
+    text.is_empty() || (text.starts_with("<start ") && text.ends_with('>'))
+}
+
+// text of the phrase recorded when a player ends the conversation explicitly
+pub(crate) fn ending_text(outcome: &str) -> String {
+    format!("<{}>", outcome)
+}
+
+pub(crate) fn is_ending_text(text: &str) -> bool {
+    text.starts_with('<') && text.ends_with('>')
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, Clone)]
@@ -112,13 +637,32 @@ impl WordCloud {
     }
 }
 
+impl WordCloud {
+    fn words(&self) -> BTreeSet<&str> {
+        self.0.split(' ').filter(|word| !word.is_empty()).collect()
+    }
+
+    // share of common words among all words of both clouds
+    pub(crate) fn overlap(&self, other: &WordCloud) -> f32 {
+        let words = self.words();
+        let other_words = other.words();
+        let union = words.union(&other_words).count();
+
+        if union == 0 {
+            0.0
+        } else {
+            words.intersection(&other_words).count() as f32 / union as f32
+        }
+    }
+}
+
 impl FromStr for WordCloud {
     type Err = serde_json::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(WordCloud::new(
             s.replace(
-                &['(', ')', ',', '\"', '.', ';', ':', '\'', '?', '!', '-'],
+                ['(', ')', ',', '\"', '.', ';', ':', '\'', '?', '!', '-'],
                 "",
             )
             .to_lowercase()
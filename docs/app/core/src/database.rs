@@ -1,17 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fmt::{Display, Error, Formatter, Result};
 use std::str::FromStr;
 
 use serde_derive::{Deserialize, Serialize};
 
-use crate::data::{GeneralPerson, Phrase, WordCloud};
+use crate::condition::Condition;
+use crate::graph::reachable;
+use crate::data::{
+    Choice, Effect, GeneralPerson, HistoryResponse, Phrase, Response, Situation, WordCloud,
+};
 
 pub const SERVER: &str = "server";
 
+const RESPONSES: (Starts, Increments<Response>) =
+    (DatabaseDifference::responses, DatabaseDifference::increments);
+const HISTORIES: (Starts, Increments<HistoryResponse>) =
+    (DatabaseDifference::histories, DatabaseDifference::history_increments);
+
+static NO_PRECEDING: BTreeMap<usize, usize> = BTreeMap::new();
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct DatabaseDifference {
     texts: HashMap<usize, usize>,
     responses: HashMap<usize, usize>,
+    #[serde(default)]
+    histories: HashMap<usize, usize>,
+    // choices added to responses before their start, by phrase and position
+    #[serde(default)]
+    increments: HashMap<usize, HashMap<usize, Response>>,
+    #[serde(default)]
+    history_increments: HashMap<usize, HashMap<usize, HistoryResponse>>,
 }
 
 impl DatabaseDifference {
@@ -19,10 +37,36 @@ impl DatabaseDifference {
         DatabaseDifference {
             texts: HashMap::new(),
             responses: HashMap::new(),
+            histories: HashMap::new(),
+            increments: HashMap::new(),
+            history_increments: HashMap::new(),
         }
     }
+
+    fn texts(&mut self) -> &mut HashMap<usize, usize> {
+        &mut self.texts
+    }
+
+    fn responses(&mut self) -> &mut HashMap<usize, usize> {
+        &mut self.responses
+    }
+
+    fn histories(&mut self) -> &mut HashMap<usize, usize> {
+        &mut self.histories
+    }
+
+    fn increments(&mut self) -> &mut HashMap<usize, HashMap<usize, Response>> {
+        &mut self.increments
+    }
+
+    fn history_increments(&mut self) -> &mut HashMap<usize, HashMap<usize, HistoryResponse>> {
+        &mut self.history_increments
+    }
 }
 
+type Starts = fn(&mut DatabaseDifference) -> &mut HashMap<usize, usize>;
+type Increments<C> = fn(&mut DatabaseDifference) -> &mut HashMap<usize, HashMap<usize, C>>;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct DifferenceManager {
     differences: HashMap<String, DatabaseDifference>,
@@ -35,49 +79,194 @@ impl DifferenceManager {
         }
     }
 
-    fn insert_text(&mut self, index: usize, start: usize) {
-        for (_, difference) in &mut self.differences {
-            difference.texts.entry(index).or_insert(start);
+    fn insert(&mut self, starts: Starts, index: usize, start: usize) {
+        for difference in self.differences.values_mut() {
+            starts(difference).entry(index).or_insert(start);
         }
     }
 
-    fn insert_response(&mut self, index: usize, start: usize) {
-        for (_, difference) in &mut self.differences {
-            difference.responses.entry(index).or_insert(start);
+    // element at position was not received by any client yet
+    fn is_pending(&mut self, starts: Starts, index: usize, position: usize) -> bool {
+        self.differences.values_mut().all(|difference| {
+            starts(difference)
+                .get(&index)
+                .is_some_and(|&start| start <= position)
+        })
+    }
+
+    fn retract(&mut self, starts: Starts, index: usize, length: usize) {
+        for difference in self.differences.values_mut() {
+            if starts(difference).get(&index) == Some(&length) {
+                starts(difference).remove(&index);
+            }
+        }
+    }
+
+    // counts a choice at position for clients that already have it
+    fn increment<C: Choice>(
+        &mut self,
+        (starts, increments): (Starts, Increments<C>),
+        index: usize,
+        position: usize,
+        choice: &C,
+    ) {
+        for difference in self.differences.values_mut() {
+            if starts(difference).get(&index).is_none_or(|&start| start > position) {
+                increments(difference)
+                    .entry(index)
+                    .or_default()
+                    .entry(position)
+                    .or_insert_with(|| choice.emptied())
+                    .add(choice);
+            }
+        }
+    }
+
+    // a choice at position was not received by any client yet
+    fn is_pending_choice<C: Choice>(
+        &mut self,
+        (starts, increments): (Starts, Increments<C>),
+        index: usize,
+        position: usize,
+    ) -> bool {
+        self.differences.values_mut().all(|difference| {
+            starts(difference).get(&index).is_some_and(|&start| start <= position)
+                || increments(difference)
+                    .get(&index)
+                    .and_then(|counts| counts.get(&position))
+                    .is_some_and(|increment| increment.count() > 0)
+        })
+    }
+
+    fn retract_choice<C: Choice>(
+        &mut self,
+        increments: Increments<C>,
+        index: usize,
+        position: usize,
+        choice: &C,
+    ) {
+        for difference in self.differences.values_mut() {
+            let increments = increments(difference);
+            if let Some(counts) = increments.get_mut(&index) {
+                if let Some(increment) = counts.get_mut(&position) {
+                    increment.remove(choice);
+                    if increment.count() == 0 {
+                        counts.remove(&position);
+                    }
+                }
+                if counts.is_empty() {
+                    increments.remove(&index);
+                }
+            }
         }
     }
 
     fn difference(&self, base: &Database, client: &str) -> Database {
         let mut database = Database::new();
         database.updated(SERVER);
+        database.generation = base.generation;
 
         if let Some(difference) = self.differences.get(client) {
-            for (&index, &text) in &difference.texts {
-                base.add_difference(
-                    &mut database,
-                    index,
-                    Some(text),
-                    difference.responses.get(&index).copied(),
-                );
-            }
+            let indices: BTreeSet<usize> = difference
+                .texts
+                .keys()
+                .chain(difference.responses.keys())
+                .chain(difference.histories.keys())
+                .chain(difference.increments.keys())
+                .chain(difference.history_increments.keys())
+                .copied()
+                .collect();
 
-            for (&index, &response) in &difference.responses {
-                if difference.texts.get(&index).is_none() {
-                    base.add_difference(&mut database, index, None, Some(response));
-                }
+            for index in indices {
+                base.add_difference(&mut database, index, difference);
             }
         }
 
+        database.index_preceding();
         database
     }
 }
 
+// broken invariant of a database, phrases are given by their index
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+enum Problem {
+    EmptyPhrase { index: usize },
+    IndexOutOfRange { cloud: WordCloud, index: usize },
+    // the indexed phrase has other words than the cloud
+    IndexMismatch { cloud: WordCloud, index: usize },
+    // the words of the phrase do not lead to it, e.g. for a duplicate phrase
+    UnindexedPhrase { index: usize },
+    DanglingResponse { index: usize, response: usize },
+    DanglingHistory { index: usize, phrase: usize },
+    DanglingCondition { index: usize, response: usize },
+    InvalidCondition { index: usize, condition: String },
+    // author of a text that does not exist
+    StrayAnnotation { index: usize, position: usize },
+    WrongSize { stored: usize, actual: usize },
+    StaleDifference { client: String, index: usize },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ValidationReport {
+    problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "StoredDatabase")]
 pub struct Database {
     pub(crate) phrases: Vec<Phrase>,
     phrase_indices: HashMap<WordCloud, usize>,
     manager: DifferenceManager,
-    size: usize
+    size: usize,
+    // for every phrase, the phrases it was said in response to
+    // with the number of such responses, rebuilt on load
+    #[serde(skip)]
+    preceding: Vec<BTreeMap<usize, usize>>,
+    // number of compactions, phrase indices of different generations do not match
+    #[serde(default)]
+    generation: u64,
+    // changes whenever phrases or responses are added or removed,
+    // for caches built on them
+    #[serde(skip)]
+    revision: u64,
+}
+
+// the stored fields of a database, the derived ones are rebuilt from them
+#[derive(Deserialize)]
+struct StoredDatabase {
+    phrases: Vec<Phrase>,
+    phrase_indices: HashMap<WordCloud, usize>,
+    manager: DifferenceManager,
+    size: usize,
+    #[serde(default)]
+    generation: u64,
+}
+
+impl From<StoredDatabase> for Database {
+    fn from(stored: StoredDatabase) -> Self {
+        let mut database = Database {
+            phrases: stored.phrases,
+            phrase_indices: stored.phrase_indices,
+            manager: stored.manager,
+            size: stored.size,
+            preceding: Vec::new(),
+            generation: stored.generation,
+            revision: 0,
+        };
+        database.index_preceding();
+        database
+    }
 }
 
 impl Database {
@@ -86,10 +275,14 @@ impl Database {
             phrases: Vec::new(),
             phrase_indices: HashMap::new(),
             manager: DifferenceManager::new(),
-            size: 0
+            size: 0,
+            preceding: Vec::new(),
+            generation: 0,
+            revision: 0,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Database> {
         serde_json::from_str(s).ok()
     }
@@ -109,22 +302,31 @@ impl Database {
     }
 
     pub fn difference(&mut self, client: &str) -> Database {
-        self.manager.difference(&self, client)
+        self.manager.difference(self, client)
     }
 
     pub fn total_clone(&self) -> Database {
         let mut database = Database::new();
         database.updated(SERVER);
+        database.generation = self.generation;
 
+        let mut whole = DatabaseDifference::new();
         for index in 0..self.phrases.len() {
-            self.add_difference(&mut database, index, Some(0), Some(0));
+            whole.texts.insert(index, 0);
+            whole.responses.insert(index, 0);
+            whole.histories.insert(index, 0);
+        }
+        for index in 0..self.phrases.len() {
+            self.add_difference(&mut database, index, &whole);
         }
 
+        database.index_preceding();
         database
     }
 
-    // merges starting from database.difference indices
-    // all response indices have to be present in database.phrase_indices
+    // merges starting from database.difference indices,
+    // responses, histories and conditions to phrases missing from
+    // database.phrase_indices or from this database are skipped
     pub fn merge(&mut self, database: Database) {
         let mut index_to_cloud: HashMap<usize, WordCloud> = HashMap::new();
         for (cloud, index) in database.phrase_indices {
@@ -132,38 +334,367 @@ impl Database {
         }
 
         let mut merged_indices = HashMap::new();
-        let difference = database.manager.differences.get(SERVER).unwrap();
+        let Some(difference) = database.manager.differences.get(SERVER) else {
+            return;
+        };
 
         for (&index, &start) in &difference.texts {
-            let texts_slice = &database.phrases[index].texts[start..];
+            let Some(first) = database.phrases.get(index).and_then(|phrase| phrase.texts.first())
+            else {
+                continue;
+            };
+            let texts_slice = database.phrases[index].texts.get(start..).unwrap_or_default();
+            let base = self
+                .get_index(first)
+                .map_or(0, |merged_index| self.phrases[merged_index].texts.len());
 
-            if let Some(merged_index) = self.insert_texts_at(
-                database.phrases[index].texts[0].as_str(),
-                texts_slice.iter().cloned(),
-            ) {
+            if let Some(merged_index) = self.insert_texts_at(first, texts_slice.iter().cloned()) {
                 merged_indices.insert(index, merged_index);
+                self.phrases[merged_index].authors.extend(
+                    database.phrases[index]
+                        .authors
+                        .iter()
+                        .filter(|(position, _)| *position >= start)
+                        .map(|(position, author)| (base + position - start, author.clone())),
+                );
+
+                if let Some(outcome) = &database.phrases[index].ending {
+                    self.phrases[merged_index].ending = Some(outcome.clone());
+                }
+                if database.phrases[index].generic {
+                    self.phrases[merged_index].generic = true;
+                }
+                if database.phrases[index].effects.is_some() {
+                    self.phrases[merged_index].effects = database.phrases[index].effects.clone();
+                }
+            }
+        }
+
+        for &index in difference.texts.keys() {
+            let Some(&merged_index) = merged_indices.get(&index) else {
+                continue;
+            };
+            for (response_index, condition) in &database.phrases[index].conditions {
+                if let Some(response_index) = self.merged_index(&index_to_cloud, *response_index) {
+                    self.phrases[merged_index].set_condition(response_index, condition);
+                }
             }
         }
 
         for (&index, &start) in &difference.responses {
-            let responses: Vec<(usize, GeneralPerson)> = database.phrases[index].responses[start..]
+            let Some(&merged_index) = merged_indices.get(&index) else {
+                continue;
+            };
+            let responses: Vec<Response> = database.phrases[index]
+                .responses
+                .get(start..)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|response| {
+                    Some(Response {
+                        index: self.merged_index(&index_to_cloud, response.index)?,
+                        ..response.clone()
+                    })
+                })
+                .collect();
+            self.insert_responses_to(merged_index, responses);
+        }
+
+        for (&index, &start) in &difference.histories {
+            let Some(&merged_index) = merged_indices.get(&index) else {
+                continue;
+            };
+            let histories: Vec<HistoryResponse> = database.phrases[index]
+                .history_responses
+                .get(start..)
+                .unwrap_or_default()
                 .iter()
-                .map(|response| {
-                    let merged_index = self
-                        .phrase_indices
-                        .get(index_to_cloud.get(&response.0).unwrap())
-                        .unwrap();
-                    (*merged_index, response.1)
+                .filter_map(|response| {
+                    Some(HistoryResponse {
+                        history: response
+                            .history
+                            .iter()
+                            .map(|&phrase| self.merged_index(&index_to_cloud, phrase))
+                            .collect::<Option<Vec<usize>>>()?,
+                        response: self.merged_index(&index_to_cloud, response.response)?,
+                        ..response.clone()
+                    })
                 })
                 .collect();
-            self.insert_responses_to(*merged_indices.get(&index).unwrap(), responses);
+            self.insert_histories_to(merged_index, histories);
         }
     }
+
+    // checks the invariants of a full database, differences are not self-contained
+    pub fn validate(&self) -> ValidationReport {
+        let length = self.phrases.len();
+        let mut problems = Vec::new();
+
+        for (cloud, &index) in &self.phrase_indices {
+            if index >= length {
+                problems.push(Problem::IndexOutOfRange {
+                    cloud: cloud.clone(),
+                    index,
+                });
+            } else if self.phrases[index]
+                .texts
+                .first()
+                .and_then(|text| WordCloud::from_str(text).ok())
+                .as_ref()
+                != Some(cloud)
+            {
+                problems.push(Problem::IndexMismatch {
+                    cloud: cloud.clone(),
+                    index,
+                });
+            }
+        }
+
+        for (index, phrase) in self.phrases.iter().enumerate() {
+            match phrase.texts.first() {
+                None => problems.push(Problem::EmptyPhrase { index }),
+                Some(text) => {
+                    if self.get_index(text) != Some(index) {
+                        problems.push(Problem::UnindexedPhrase { index });
+                    }
+                }
+            }
+
+            for response in &phrase.responses {
+                if response.index >= length {
+                    problems.push(Problem::DanglingResponse {
+                        index,
+                        response: response.index,
+                    });
+                }
+            }
+            for response in &phrase.history_responses {
+                for &other in response.history.iter().chain([&response.response]) {
+                    if other >= length {
+                        problems.push(Problem::DanglingHistory {
+                            index,
+                            phrase: other,
+                        });
+                    }
+                }
+            }
+            for (response, condition) in &phrase.conditions {
+                if *response >= length {
+                    problems.push(Problem::DanglingCondition {
+                        index,
+                        response: *response,
+                    });
+                } else if !condition.is_empty() && Condition::from_str(condition).is_err() {
+                    problems.push(Problem::InvalidCondition {
+                        index,
+                        condition: condition.clone(),
+                    });
+                }
+            }
+
+            let stray_authors = phrase
+                .authors
+                .iter()
+                .filter(|(position, _)| *position >= phrase.texts.len());
+            for &(position, _) in stray_authors {
+                problems.push(Problem::StrayAnnotation { index, position });
+            }
+        }
+
+        let actual = self.phrases.iter().map(Phrase::response_count).sum();
+        if self.size != actual {
+            problems.push(Problem::WrongSize {
+                stored: self.size,
+                actual,
+            });
+        }
+
+        for (client, difference) in &self.manager.differences {
+            let mut indices: Vec<usize> = difference
+                .texts
+                .keys()
+                .chain(difference.responses.keys())
+                .chain(difference.histories.keys())
+                .chain(difference.increments.keys())
+                .copied()
+                .filter(|&index| index >= length)
+                .collect();
+            indices.sort();
+            indices.dedup();
+            for index in indices {
+                problems.push(Problem::StaleDifference {
+                    client: client.clone(),
+                    index,
+                });
+            }
+        }
+
+        ValidationReport { problems }
+    }
+
+    // drops references to missing phrases and rebuilds the derived fields,
+    // starts a new generation if responses were dropped,
+    // returns the problems that are left, e.g. empty or duplicate phrases
+    pub fn repair(&mut self) -> ValidationReport {
+        let length = self.phrases.len();
+        let mut shifted = false;
+
+        for phrase in &mut self.phrases {
+            let responses = phrase.responses.len();
+            phrase.responses.retain(|response| response.index < length);
+            let texts = phrase.texts.len();
+            phrase.authors.retain(|(position, _)| *position < texts);
+            let histories = phrase.history_responses.len();
+            phrase.history_responses.retain(|response| {
+                response.response < length && response.history.iter().all(|&other| other < length)
+            });
+            shifted |= responses != phrase.responses.len()
+                || histories != phrase.history_responses.len();
+            phrase.conditions.retain(|(response, condition)| {
+                *response < length
+                    && (condition.is_empty() || Condition::from_str(condition).is_ok())
+            });
+            phrase.parse_conditions();
+        }
+
+        // differences point at response positions, which moved if any response
+        // was removed, so every client has to resync as after compaction
+        for difference in self.manager.differences.values_mut() {
+            if shifted {
+                *difference = DatabaseDifference::new();
+            }
+            difference.texts.retain(|&index, _| index < length);
+            difference.responses.retain(|&index, _| index < length);
+            difference.histories.retain(|&index, _| index < length);
+            difference.increments.retain(|&index, _| index < length);
+        }
+        if shifted {
+            self.generation += 1;
+        }
+        self.index_phrases();
+
+        self.validate()
+    }
+
+    // removes phrases no conversation can reach or without texts, merges phrases
+    // with the same cloud, drops duplicate texts and condition tombstones,
+    // then renumbers the phrases left;
+    // chats of the database become invalid and every client has to resync,
+    // returns the number of removed phrases
+    pub fn compact(&mut self) -> usize {
+        self.repair();
+        let reachable = reachable(self);
+
+        // phrases with the same cloud are merged into the indexed one,
+        // which is kept if any of them can be reached
+        let owners: Vec<usize> = self
+            .phrases
+            .iter()
+            .enumerate()
+            .map(|(index, phrase)| {
+                phrase
+                    .texts
+                    .first()
+                    .and_then(|text| WordCloud::from_str(text).ok())
+                    .and_then(|cloud| self.phrase_indices.get(&cloud).copied())
+                    .unwrap_or(index)
+            })
+            .collect();
+        let mut kept_owners = vec![false; self.phrases.len()];
+        for (index, phrase) in self.phrases.iter().enumerate() {
+            if reachable[index] && !phrase.texts.is_empty() {
+                kept_owners[owners[index]] = true;
+            }
+        }
+        let mut renumbered = vec![None; self.phrases.len()];
+        let mut kept = 0;
+        for index in 0..self.phrases.len() {
+            if kept_owners[index] {
+                renumbered[index] = Some(kept);
+                kept += 1;
+            }
+        }
+        for index in 0..self.phrases.len() {
+            renumbered[index] = renumbered[owners[index]];
+        }
+
+        let removed = self.phrases.len() - kept;
+        let mut phrases: Vec<Option<Phrase>> =
+            std::mem::take(&mut self.phrases).into_iter().map(Some).collect();
+        for index in 0..phrases.len() {
+            let owner = owners[index];
+            if owner != index && kept_owners[owner] {
+                if let Some(duplicate) = phrases[index].take() {
+                    absorb(phrases[owner].as_mut().unwrap(), duplicate);
+                }
+            }
+        }
+        self.phrases = phrases
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| kept_owners[*index])
+            .filter_map(|(_, phrase)| Some(renumber(phrase?, &renumbered)))
+            .collect();
+
+        for difference in self.manager.differences.values_mut() {
+            *difference = DatabaseDifference::new();
+        }
+        self.generation += 1;
+        self.index_phrases();
+        removed
+    }
+
+    // aggregates responses and history responses stored once per choice or per character,
+    // clients have to resync if anything was merged, returns the number of merged entries
+    pub fn migrate(&mut self) -> usize {
+        let mut merged = 0;
+        for phrase in &mut self.phrases {
+            let (responses, merged_responses) = aggregate(std::mem::take(&mut phrase.responses));
+            let (histories, merged_histories) =
+                aggregate(std::mem::take(&mut phrase.history_responses));
+            phrase.responses = responses;
+            phrase.history_responses = histories;
+            merged += merged_responses + merged_histories;
+        }
+
+        if merged > 0 {
+            for difference in self.manager.differences.values_mut() {
+                *difference = DatabaseDifference::new();
+            }
+            self.generation += 1;
+            self.index_phrases();
+        }
+        merged
+    }
+
+    // whether the database has to be replaced by a fresh total clone
+    // of the server instead of merging its difference
+    pub fn needs_resync(&self, difference: &Database) -> bool {
+        self.generation != difference.generation
+    }
+
+    // replaces the database by a total clone of the server,
+    // local changes not sent yet are kept
+    pub fn resync(&mut self, database: Database) {
+        let pending = self.difference(SERVER);
+        let revision = self.revision;
+        *self = Database::new();
+        self.revision = revision + 1;
+        self.generation = database.generation;
+        self.updated(SERVER);
+        self.merge(database);
+        self.updated(SERVER);
+        self.merge(pending);
+    }
 }
 
 impl Database {
     pub(crate) fn get_start_index(&self) -> Option<usize> {
-        self.phrase_indices.get(&WordCloud::from_str("").unwrap()).copied()
+        self.get_index("")
+    }
+
+    pub(crate) fn get_index(&self, text: &str) -> Option<usize> {
+        self.phrase_indices.get(&WordCloud::from_str(text).ok()?).copied()
     }
 
     pub(crate) fn insert_texts_at<I: IntoIterator<Item = String>>(
@@ -178,13 +709,21 @@ impl Database {
             if let Some(index) = phrase_index {
                 let text_vec: Vec<String> = texts.into_iter().collect();
                 if !text_vec.is_empty() {
-                    self.manager
-                        .insert_text(index, self.phrases[index].texts.len());
+                    self.manager.insert(
+                        DatabaseDifference::texts,
+                        index,
+                        self.phrases[index].texts.len(),
+                    );
                     self.phrases[index].texts.extend(text_vec);
                 }
             } else {
-                self.manager.insert_text(real_index, 0);
+                self.manager
+                    .insert(DatabaseDifference::texts, real_index, 0);
                 self.phrases.push(Phrase::new());
+                self.revision += 1;
+                if self.preceding.len() < self.phrases.len() {
+                    self.preceding.push(BTreeMap::new());
+                }
                 self.phrases[real_index].texts.extend(texts);
                 self.phrase_indices.insert(cloud, real_index);
             }
@@ -195,29 +734,312 @@ impl Database {
         }
     }
 
-    pub(crate) fn insert_responses_to<I: IntoIterator<Item = (usize, GeneralPerson)>>(
+    // marks phrase as an ending of the conversation with the given outcome
+    pub(crate) fn mark_ending(&mut self, text: &str, outcome: &str) -> bool {
+        self.mark_phrase(text, |phrase| phrase.ending = Some(outcome.to_string()))
+    }
+
+    // marks phrase as a generic continuation usable after any dead end
+    pub(crate) fn mark_generic(&mut self, text: &str) -> bool {
+        self.mark_phrase(text, |phrase| phrase.generic = true)
+    }
+
+    pub(crate) fn set_effects(&mut self, text: &str, effects: Vec<Effect>) -> bool {
+        self.mark_phrase(text, |phrase| phrase.effects = Some(effects))
+    }
+
+    // restricts who can answer text with response_text, fails on invalid conditions
+    pub(crate) fn set_condition(&mut self, text: &str, response_text: &str, condition: &str) -> bool {
+        if !condition.is_empty() && Condition::from_str(condition).is_err() {
+            return false;
+        }
+
+        if let Some(response_index) = self.get_index(response_text) {
+            self.mark_phrase(text, |phrase| phrase.set_condition(response_index, condition))
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn generic_indices(&self) -> Vec<usize> {
+        (0..self.phrases.len())
+            .filter(|&index| self.phrases[index].generic)
+            .collect()
+    }
+
+    // phrase with responses sharing the largest part of its words with the given one
+    pub(crate) fn most_similar(&self, index: usize, threshold: f32) -> Option<usize> {
+        let cloud = WordCloud::from_str(&self.phrases[index].texts[0]).ok()?;
+
+        self.phrase_indices
+            .iter()
+            .filter(|(_, &other)| other != index && !self.phrases[other].responses.is_empty())
+            .map(|(other_cloud, &other)| (other, cloud.overlap(other_cloud)))
+            .filter(|&(_, overlap)| overlap >= threshold)
+            .max_by(|lhs, rhs| lhs.1.total_cmp(&rhs.1).then(rhs.0.cmp(&lhs.0)))
+            .map(|(other, _)| other)
+    }
+
+    // phrases the given one was said in response to, with the number of such responses
+    pub(crate) fn preceding(&self, index: usize) -> &BTreeMap<usize, usize> {
+        self.preceding.get(index).unwrap_or(&NO_PRECEDING)
+    }
+
+    pub(crate) fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // number of responses needed to reach any of the targets from each phrase
+    pub(crate) fn distances_to(&self, targets: &[usize]) -> Vec<Option<usize>> {
+        let mut distances = vec![None; self.phrases.len()];
+        let mut queue = VecDeque::new();
+        for &target in targets {
+            if distances[target].is_none() {
+                distances[target] = Some(0);
+                queue.push_back(target);
+            }
+        }
+
+        while let Some(index) = queue.pop_front() {
+            let distance = distances[index].map(|distance| distance + 1);
+            for &previous in self.preceding(index).keys() {
+                if distances[previous].is_none() {
+                    distances[previous] = distance;
+                    queue.push_back(previous);
+                }
+            }
+        }
+
+        distances
+    }
+
+    fn mark_phrase<F: FnOnce(&mut Phrase)>(&mut self, text: &str, mark: F) -> bool {
+        if let Some(index) = self.get_index(text) {
+            self.manager.insert(
+                DatabaseDifference::texts,
+                index,
+                self.phrases[index].texts.len(),
+            );
+            mark(&mut self.phrases[index]);
+            true
+        } else {
+            false
+        }
+    }
+
+    // adds the choices to the count of the same earlier ones,
+    // new choices are appended to the responses of the phrase
+    pub(crate) fn insert_responses_to<I: IntoIterator<Item = Response>>(
         &mut self,
         index: usize,
         responses: I,
     ) {
+        for response in responses {
+            self.revision += 1;
+            self.size += response.count as usize;
+            *self.preceding[response.index].entry(index).or_default() += response.count as usize;
+
+            let recorded = &mut self.phrases[index].responses;
+            if let Some(position) = recorded.iter().position(|other| other.same_choice(&response)) {
+                recorded[position].add(&response);
+                self.manager.increment(RESPONSES, index, position, &response);
+            } else {
+                self.manager
+                    .insert(DatabaseDifference::responses, index, recorded.len());
+                recorded.push(response);
+            }
+        }
+    }
+
+    // credits the last text variant of the phrase to the persona who typed it,
+    // it is synced together with that text
+    pub(crate) fn credit_last_text(&mut self, index: usize, author: GeneralPerson) {
+        let position = self.phrases[index].texts.len() - 1;
+        self.phrases[index].authors.push((position, author));
+    }
+
+    pub(crate) fn insert_histories_to<I: IntoIterator<Item = HistoryResponse>>(
+        &mut self,
+        index: usize,
+        histories: I,
+    ) {
+        for history in histories {
+            let recorded = &mut self.phrases[index].history_responses;
+            if let Some(position) = recorded.iter().position(|other| other.same_choice(&history)) {
+                recorded[position].add(&history);
+                self.manager.increment(HISTORIES, index, position, &history);
+            } else {
+                self.manager
+                    .insert(DatabaseDifference::histories, index, recorded.len());
+                recorded.push(history);
+            }
+        }
+    }
+
+    // removes one choice of a response if no client has received it yet,
+    // the response itself only goes when it is the last one of the phrase
+    pub(crate) fn retract_response_from(&mut self, index: usize, response: &Response) -> bool {
+        let responses = &self.phrases[index].responses;
+        let position = match responses.iter().position(|other| other.same_choice(response)) {
+            Some(position) => position,
+            None => return false,
+        };
+        let removed = responses[position].count == 1;
+
+        if (removed && position + 1 != responses.len())
+            || !self.manager.is_pending_choice(RESPONSES, index, position)
+        {
+            return false;
+        }
+
+        if removed {
+            self.phrases[index].responses.pop();
+            self.manager
+                .retract(DatabaseDifference::responses, index, position);
+        } else {
+            self.phrases[index].responses[position].remove(response);
+            self.manager
+                .retract_choice(DatabaseDifference::increments, index, position, response);
+        }
+        self.unlink(index, response.index);
+        self.size -= 1;
+        true
+    }
+
+    // removes one choice of a history response like retract_response_from
+    pub(crate) fn retract_history_from(&mut self, index: usize, history: &HistoryResponse) -> bool {
+        let histories = &self.phrases[index].history_responses;
+        let position = match histories.iter().position(|other| other.same_choice(history)) {
+            Some(position) => position,
+            None => return false,
+        };
+        let removed = histories[position].count == 1;
+
+        if (removed && position + 1 != histories.len())
+            || !self.manager.is_pending_choice(HISTORIES, index, position)
+        {
+            return false;
+        }
+
+        if removed {
+            self.phrases[index].history_responses.pop();
+            self.manager
+                .retract(DatabaseDifference::histories, index, position);
+        } else {
+            self.phrases[index].history_responses[position].remove(history);
+            self.manager.retract_choice(
+                DatabaseDifference::history_increments,
+                index,
+                position,
+                history,
+            );
+        }
+        true
+    }
+
+    // removes the last text variant of a phrase if no client has received it yet,
+    // the phrase itself is dropped when it was created by this text, a phrase
+    // that cannot be dropped keeps its only text
+    pub(crate) fn retract_text_at(&mut self, index: usize, text: &str) -> bool {
+        let phrase = &self.phrases[index];
+        let position = phrase.texts.len().wrapping_sub(1);
+        let droppable = phrase.responses.is_empty()
+            && phrase.history_responses.is_empty()
+            && index + 1 == self.phrases.len();
+
+        if phrase.texts.last().map(String::as_str) != Some(text)
+            || (position == 0 && !droppable)
+            || !self
+                .manager
+                .is_pending(DatabaseDifference::texts, index, position)
+        {
+            return false;
+        }
+
+        self.phrases[index].texts.pop();
+        self.phrases[index]
+            .authors
+            .retain(|(recorded, _)| *recorded != position);
         self.manager
-            .insert_response(index, self.phrases[index].responses.len());
-        self.phrases[index].responses.extend(responses.into_iter().map(|x| {
-            self.size += 1;
-            x
-        }));
+            .retract(DatabaseDifference::texts, index, position);
+
+        if position == 0 {
+            self.phrases.pop();
+            self.preceding.pop();
+            self.revision += 1;
+            self.phrase_indices.remove(&WordCloud::from_str(text).unwrap());
+        }
+        true
     }
 
-    fn add_difference(
+    // rebuilds the fields derived from the phrases
+    fn index_phrases(&mut self) {
+        self.phrase_indices.clear();
+        for (index, phrase) in self.phrases.iter().enumerate() {
+            if let Some(cloud) = phrase
+                .texts
+                .first()
+                .and_then(|text| WordCloud::from_str(text).ok())
+            {
+                self.phrase_indices.entry(cloud).or_insert(index);
+            }
+        }
+
+        self.size = self.phrases.iter().map(Phrase::response_count).sum();
+        self.index_preceding();
+    }
+
+    // differences and loaded documents can answer with phrases they do not contain,
+    // such responses are left out of the index
+    fn index_preceding(&mut self) {
+        let size = self.phrases.len();
+        self.revision += 1;
+        self.preceding = vec![BTreeMap::new(); size];
+        for (index, phrase) in self.phrases.iter().enumerate() {
+            for response in phrase.responses.iter().filter(|response| response.index < size) {
+                *self.preceding[response.index].entry(index).or_default() +=
+                    response.count as usize;
+            }
+        }
+    }
+
+    fn unlink(&mut self, index: usize, response: usize) {
+        self.revision += 1;
+        if let Some(preceding) = self.preceding.get_mut(response) {
+            if let Some(count) = preceding.get_mut(&index) {
+                *count -= 1;
+                if *count == 0 {
+                    preceding.remove(&index);
+                }
+            }
+        }
+    }
+
+    fn merged_index(
         &self,
-        database: &mut Database,
+        index_to_cloud: &HashMap<usize, WordCloud>,
         index: usize,
-        text: Option<usize>,
-        response: Option<usize>,
-    ) {
+    ) -> Option<usize> {
+        self.phrase_indices.get(index_to_cloud.get(&index)?).copied()
+    }
+
+    fn add_phrase_index(&self, database: &mut Database, index: usize) {
+        database.phrase_indices.insert(
+            WordCloud::from_str(&self.phrases[index].texts[0]).unwrap(),
+            index,
+        );
+    }
+
+    // adds the part of the phrase at index the difference starts from
+    fn add_difference(&self, database: &mut Database, index: usize, from: &DatabaseDifference) {
+        let text = from.texts.get(&index).copied();
+        let response = from.responses.get(&index).copied();
+        let history = from.histories.get(&index).copied();
         let length = database.phrases.len();
         let difference = database.manager.differences.get_mut(SERVER).unwrap();
 
+        // phrases marked without new texts still have to be sent
+        let text = text.filter(|&start| start < self.phrases[index].texts.len());
         let texts = if let Some(text_start) = text {
             difference.texts.insert(length, 0);
             self.phrases[index].texts[text_start..].to_vec()
@@ -225,22 +1047,63 @@ impl Database {
             difference.texts.insert(length, 1);
             vec![self.phrases[index].texts[0].clone()]
         };
+        // authors travel with the texts they typed
+        let authors = text
+            .map(|text_start| {
+                self.phrases[index]
+                    .authors
+                    .iter()
+                    .filter(|(position, _)| *position >= text_start)
+                    .map(|(position, author)| (position - text_start, author.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
 
-        let responses = if let Some(response_start) = response {
+        let mut responses = response
+            .map(|response_start| self.phrases[index].responses[response_start..].to_vec())
+            .unwrap_or_default();
+        // later choices of responses the client has are sent as separate counts
+        responses.extend(sorted_increments(from.increments.get(&index)));
+        if !responses.is_empty() || response.is_some() {
             difference.responses.insert(length, 0);
-            for &(response_index, _) in &self.phrases[index].responses[response_start..] {
-                database.phrase_indices.insert(
-                    WordCloud::from_str(&self.phrases[response_index].texts[0]).unwrap(),
-                    response_index,
-                );
+        }
+        for response in &responses {
+            self.add_phrase_index(database, response.index);
+        }
+
+        for &(response_index, _) in &self.phrases[index].conditions {
+            self.add_phrase_index(database, response_index);
+        }
+
+        let mut history_responses = history
+            .map(|history_start| self.phrases[index].history_responses[history_start..].to_vec())
+            .unwrap_or_default();
+        history_responses.extend(sorted_increments(from.history_increments.get(&index)));
+        if !history_responses.is_empty() || history.is_some() {
+            let difference = database.manager.differences.get_mut(SERVER).unwrap();
+            difference.histories.insert(length, 0);
+        }
+        for response in &history_responses {
+            for &phrase_index in response.history.iter().chain([&response.response]) {
+                self.add_phrase_index(database, phrase_index);
             }
-            self.phrases[index].responses[response_start..].to_vec()
-        } else {
-            Vec::new()
-        };
+        }
 
-        database.size += responses.len();
-        database.phrases.push(Phrase { texts, responses });
+        database.size += responses
+            .iter()
+            .map(|response| response.count as usize)
+            .sum::<usize>();
+        database.phrases.push(Phrase {
+            texts,
+            responses,
+            history_responses,
+            ending: self.phrases[index].ending.clone(),
+            generic: self.phrases[index].generic,
+            effects: self.phrases[index].effects.clone(),
+            conditions: self.phrases[index].conditions.clone(),
+            parsed_conditions: self.phrases[index].parsed_conditions.clone(),
+            authors,
+        });
     }
 }
 
@@ -255,11 +1118,107 @@ impl Display for Database {
         write!(
             f,
             "{}",
-            serde_json::to_string(&self).map_err(|_| Error::default())?
+            serde_json::to_string(&self).map_err(|_| Error)?
         )
     }
 }
 
+// moves a kept phrase to the new indices, dropping duplicate texts,
+// counting equal choices together, condition tombstones and histories through removed phrases
+fn renumber(mut phrase: Phrase, renumbered: &[Option<usize>]) -> Phrase {
+    let mut texts: Vec<String> = Vec::new();
+    let mut authors = Vec::new();
+    for (position, text) in phrase.texts.iter().enumerate() {
+        let kept_position = texts.iter().position(|kept| kept == text).unwrap_or_else(|| {
+            texts.push(text.clone());
+            texts.len() - 1
+        });
+        if let Some(author) = phrase.author(position) {
+            if !authors.iter().any(|(kept, _)| *kept == kept_position) {
+                authors.push((kept_position, author.clone()));
+            }
+        }
+    }
+    phrase.texts = texts;
+    phrase.authors = authors;
+
+    let kept = phrase.responses.into_iter().filter_map(|mut response| {
+        response.index = renumbered[response.index]?;
+        Some(response)
+    });
+    phrase.responses = aggregate(kept).0;
+    let kept = phrase.history_responses.into_iter().filter_map(|response| {
+        Some(HistoryResponse {
+            history: response
+                .history
+                .iter()
+                .map(|&index| renumbered[index])
+                .collect::<Option<Vec<usize>>>()?,
+            response: renumbered[response.response]?,
+            ..response
+        })
+    });
+    phrase.history_responses = aggregate(kept).0;
+    // later conditions for the same response replace earlier ones
+    let mut conditions: Vec<(usize, String)> = Vec::new();
+    for (index, condition) in phrase.conditions {
+        if let Some(index) = renumbered[index].filter(|_| !condition.is_empty()) {
+            conditions.retain(|(kept, _)| *kept != index);
+            conditions.push((index, condition));
+        }
+    }
+    phrase.conditions = conditions;
+    phrase.parse_conditions();
+    phrase
+}
+
+// counts equal choices together, returns them with the number of merged entries
+fn aggregate<C: Choice, I: IntoIterator<Item = C>>(choices: I) -> (Vec<C>, usize) {
+    let mut aggregated: Vec<C> = Vec::new();
+    let mut merged = 0;
+    for choice in choices {
+        match aggregated.iter_mut().find(|kept| kept.same_choice(&choice)) {
+            Some(kept) => {
+                kept.add(&choice);
+                merged += 1;
+            }
+            None => aggregated.push(choice),
+        }
+    }
+    (aggregated, merged)
+}
+
+// later choices of the phrase the client already has, by position
+fn sorted_increments<C: Choice>(increments: Option<&HashMap<usize, C>>) -> Vec<C> {
+    let mut increments: Vec<(usize, &C)> = increments
+        .into_iter()
+        .flatten()
+        .map(|(&position, increment)| (position, increment))
+        .collect();
+    increments.sort_by_key(|&(position, _)| position);
+    increments.into_iter().map(|(_, increment)| increment.clone()).collect()
+}
+
+// adds the texts, choices and annotations of a phrase with the same cloud,
+// the annotations of the phrase itself win
+fn absorb(phrase: &mut Phrase, duplicate: Phrase) {
+    let base = phrase.texts.len();
+    phrase.texts.extend(duplicate.texts);
+    phrase.authors.extend(
+        duplicate
+            .authors
+            .into_iter()
+            .map(|(position, author)| (base + position, author)),
+    );
+    phrase.responses.extend(duplicate.responses);
+    phrase.history_responses.extend(duplicate.history_responses);
+    let conditions = std::mem::replace(&mut phrase.conditions, duplicate.conditions);
+    phrase.conditions.extend(conditions);
+    phrase.ending = phrase.ending.take().or(duplicate.ending);
+    phrase.generic |= duplicate.generic;
+    phrase.effects = phrase.effects.take().or(duplicate.effects);
+}
+
 fn vec_to_multiset<T: std::hash::Hash + std::cmp::Eq + Clone>(vec: &Vec<T>) -> HashMap<T, u32> {
     let mut map = HashMap::new();
 
@@ -270,6 +1229,21 @@ fn vec_to_multiset<T: std::hash::Hash + std::cmp::Eq + Clone>(vec: &Vec<T>) -> H
     map
 }
 
+// number of choices of every response, however they are split into entries
+fn response_counts<F: Fn(usize) -> usize>(
+    responses: &[Response],
+    map_index: F,
+) -> HashMap<(usize, &GeneralPerson, &Situation), u32> {
+    let mut map = HashMap::new();
+
+    for response in responses {
+        *map.entry((map_index(response.index), &response.person, &response.situation))
+            .or_default() += response.count;
+    }
+
+    map
+}
+
 impl PartialEq for Database {
     fn eq(&self, other: &Self) -> bool {
         let mut to_other = HashMap::new();
@@ -290,22 +1264,61 @@ impl PartialEq for Database {
             return false;
         }
 
-        for (index, phrase) in (&self.phrases).iter().enumerate() {
+        for (index, phrase) in self.phrases.iter().enumerate() {
             let other_phrase = &other.phrases[to_other[&index]];
-            if vec_to_multiset(&phrase.texts) != vec_to_multiset(&other_phrase.texts) {
+            if phrase.ending != other_phrase.ending
+                || phrase.generic != other_phrase.generic
+                || phrase.effects != other_phrase.effects
+            {
                 return false;
             }
 
-            let mapped_responses: Vec<(usize, GeneralPerson)> = phrase
-                .responses
+            let mapped_conditions: Vec<(usize, String)> = phrase
+                .conditions
+                .iter()
+                .map(|(index, condition)| (to_other[index], condition.clone()))
+                .collect();
+            if vec_to_multiset(&mapped_conditions) != vec_to_multiset(&other_phrase.conditions) {
+                return false;
+            }
+
+            let texts: Vec<(&String, Option<&GeneralPerson>)> = phrase
+                .texts
+                .iter()
+                .enumerate()
+                .map(|(position, text)| (text, phrase.author(position)))
+                .collect();
+            let other_texts: Vec<(&String, Option<&GeneralPerson>)> = other_phrase
+                .texts
                 .iter()
-                .map(|(index, person)| (to_other[index], *person))
+                .enumerate()
+                .map(|(position, text)| (text, other_phrase.author(position)))
+                .collect();
+            if vec_to_multiset(&texts) != vec_to_multiset(&other_texts) {
+                return false;
+            }
+
+            let mapped_responses = response_counts(&phrase.responses, |index| to_other[&index]);
+            if mapped_responses != response_counts(&other_phrase.responses, |index| index) {
+                return false;
+            }
+
+            let mapped_histories: Vec<HistoryResponse> = phrase
+                .history_responses
+                .iter()
+                .map(|response| HistoryResponse {
+                    history: response.history.iter().map(|index| to_other[index]).collect(),
+                    response: to_other[&response.response],
+                    ..response.clone()
+                })
                 .collect();
-            if vec_to_multiset(&mapped_responses) != vec_to_multiset(&other_phrase.responses) {
+            if vec_to_multiset(&mapped_histories)
+                != vec_to_multiset(&other_phrase.history_responses)
+            {
                 return false;
             }
         }
 
-        return true;
+        true
     }
 }
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use serde_derive::Serialize;
+
+use crate::data::{is_start_text, Job, JOBS};
+use crate::database::Database;
+
+// content problems of a database, phrases are given by their first text or
+// <empty> if they have none
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct GraphReport {
+    // not reachable from any start phrase through responses
+    unreachable: Vec<String>,
+    // no responses and not an ending of the conversation
+    dead_ends: Vec<String>,
+    // (phrase, response index) pairs pointing past the last phrase
+    dangling: Vec<(String, usize)>,
+    // number of phrases with responses that some persona of the job answered
+    coverage: BTreeMap<Job, usize>,
+    // jobs that answered no phrase at all
+    uncovered_jobs: Vec<Job>,
+    cycles: Vec<Vec<String>>,
+}
+
+impl GraphReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+// phrases that can be said in some conversation, generic phrases count as reachable
+// since chats offer them at dead ends
+pub(crate) fn reachable(database: &Database) -> Vec<bool> {
+    let phrases = &database.phrases;
+    let mut reachable = vec![false; phrases.len()];
+    let mut queue: VecDeque<usize> = (0..phrases.len())
+        .filter(|&index| {
+            phrases[index].generic || phrases[index].texts.first().is_some_and(|text| is_start_text(text))
+        })
+        .collect();
+    for &root in &queue {
+        reachable[root] = true;
+    }
+
+    while let Some(index) = queue.pop_front() {
+        for response in &phrases[index].responses {
+            if response.index < phrases.len() && !reachable[response.index] {
+                reachable[response.index] = true;
+                queue.push_back(response.index);
+            }
+        }
+    }
+    reachable
+}
+
+pub fn report(database: &Database) -> GraphReport {
+    let phrases = &database.phrases;
+    let text = |index: usize| {
+        phrases[index]
+            .texts
+            .first()
+            .cloned()
+            .unwrap_or_else(|| "<empty>".to_string())
+    };
+
+    let mut dangling = Vec::new();
+    for (index, phrase) in phrases.iter().enumerate() {
+        let responses = phrase.responses.iter().map(|response| response.index);
+        let histories = phrase
+            .history_responses
+            .iter()
+            .flat_map(|response| response.history.iter().chain([&response.response]).copied());
+        for response in responses.chain(histories) {
+            if response >= phrases.len() {
+                dangling.push((text(index), response));
+            }
+        }
+    }
+
+    let reachable = reachable(database);
+
+    let mut coverage: BTreeMap<Job, usize> = JOBS.iter().map(|&job| (job, 0)).collect();
+    for phrase in phrases {
+        let mut jobs: Vec<Job> = phrase
+            .responses
+            .iter()
+            .map(|response| response.person.job())
+            .collect();
+        jobs.sort();
+        jobs.dedup();
+        for job in jobs {
+            *coverage.entry(job).or_default() += 1;
+        }
+    }
+
+    GraphReport {
+        unreachable: (0..phrases.len())
+            .filter(|&index| !reachable[index])
+            .map(text)
+            .collect(),
+        dead_ends: (0..phrases.len())
+            .filter(|&index| phrases[index].responses.is_empty() && phrases[index].ending.is_none())
+            .map(text)
+            .collect(),
+        dangling,
+        uncovered_jobs: JOBS
+            .iter()
+            .copied()
+            .filter(|job| coverage[job] == 0)
+            .collect(),
+        coverage,
+        cycles: cycles(database)
+            .into_iter()
+            .map(|component| component.into_iter().map(text).collect())
+            .collect(),
+    }
+}
+
+// phrases each phrase can be answered with, without duplicates
+fn successors(database: &Database) -> Vec<Vec<usize>> {
+    let size = database.phrases.len();
+    database
+        .phrases
+        .iter()
+        .map(|phrase| {
+            let mut responses: Vec<usize> = phrase
+                .responses
+                .iter()
+                .map(|response| response.index)
+                .filter(|&response| response < size)
+                .collect();
+            responses.sort();
+            responses.dedup();
+            responses
+        })
+        .collect()
+}
+
+// state of tarjan's strongly connected components search
+struct Tarjan {
+    visited: usize,
+    order: Vec<Option<usize>>,
+    lowlink: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+}
+
+impl Tarjan {
+    fn new(size: usize) -> Self {
+        Tarjan {
+            visited: 0,
+            order: vec![None; size],
+            lowlink: vec![0; size],
+            on_stack: vec![false; size],
+            stack: Vec::new(),
+        }
+    }
+
+    fn visit(&mut self, index: usize) {
+        self.order[index] = Some(self.visited);
+        self.lowlink[index] = self.visited;
+        self.visited += 1;
+        self.stack.push(index);
+        self.on_stack[index] = true;
+    }
+
+    // pops the component rooted at index once all its phrases are visited
+    fn component(&mut self, index: usize) -> Option<Vec<usize>> {
+        if Some(self.lowlink[index]) != self.order[index] {
+            return None;
+        }
+
+        let mut component = Vec::new();
+        while let Some(member) = self.stack.pop() {
+            self.on_stack[member] = false;
+            component.push(member);
+            if member == index {
+                break;
+            }
+        }
+        Some(component)
+    }
+}
+
+// groups of phrases that can follow each other forever, i.e. strongly connected
+// components of the response graph with a cycle, largest first
+pub(crate) fn cycles(database: &Database) -> Vec<Vec<usize>> {
+    let successors = successors(database);
+    let mut tarjan = Tarjan::new(successors.len());
+    let mut components = Vec::new();
+
+    for root in 0..successors.len() {
+        if tarjan.order[root].is_some() {
+            continue;
+        }
+
+        tarjan.visit(root);
+        let mut calls = vec![(root, 0)];
+        while let Some((index, next)) = calls.last_mut() {
+            let index = *index;
+            if let Some(&child) = successors[index].get(*next) {
+                *next += 1;
+                match tarjan.order[child] {
+                    None => {
+                        tarjan.visit(child);
+                        calls.push((child, 0));
+                    }
+                    Some(child_order) if tarjan.on_stack[child] => {
+                        tarjan.lowlink[index] = tarjan.lowlink[index].min(child_order);
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+
+            calls.pop();
+            if let Some(&(parent, _)) = calls.last() {
+                tarjan.lowlink[parent] = tarjan.lowlink[parent].min(tarjan.lowlink[index]);
+            }
+
+            if let Some(mut component) = tarjan.component(index) {
+                if component.len() > 1 || successors[index].contains(&index) {
+                    component.sort();
+                    components.push(component);
+                }
+            }
+        }
+    }
+
+    components.sort_by(|lhs, rhs| rhs.len().cmp(&lhs.len()).then(lhs.cmp(rhs)));
+    components
+}
@@ -1,9 +1,18 @@
+pub mod bake;
 pub mod database;
+pub mod graph;
 pub mod log;
+pub mod simulation;
 pub mod wasm;
 
 mod chat;
+mod condition;
 mod data;
+mod paths;
+mod template;
+mod transcript;
 
+#[cfg(test)]
+mod test_chat;
 #[cfg(test)]
 mod test_database;
@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_derive::Serialize;
+
+use crate::data::{start_text, GeneralPerson};
+use crate::database::Database;
+use crate::transcript::{Transcript, Turn, TurnKind};
+
+// conversation found by best_paths together with its probability
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct Path {
+    pub(crate) probability: f32,
+    pub(crate) transcript: Transcript,
+}
+
+// most probable conversations of at most length turns opened from the start phrase
+// of the context, found by a beam search keeping count paths per turn;
+// a path never returns to a phrase it has already visited
+pub(crate) fn best_paths(
+    database: &Database,
+    person: &GeneralPerson,
+    context: &str,
+    length: usize,
+    count: usize,
+) -> Vec<Path> {
+    let Some(start) = database.get_index(&start_text(context)) else {
+        return Vec::new();
+    };
+
+    let mut beam: Vec<(f32, Vec<usize>)> = vec![(1.0, Vec::new())];
+    let mut finished = Vec::new();
+
+    for turn in 0..length {
+        let mut speaker = person.clone();
+        speaker.youtalk = person.youtalk != (turn % 2 == 1);
+
+        let mut next = Vec::new();
+        for (probability, path) in beam {
+            let current = path.last().copied().unwrap_or(start);
+            let transitions = transitions(database, current, &speaker);
+            let mut extended_any = false;
+
+            for (index, transition) in transitions {
+                if index == start || path.contains(&index) {
+                    continue;
+                }
+                extended_any = true;
+
+                let mut extended = path.clone();
+                extended.push(index);
+                if database.phrases[index].ending.is_some() {
+                    finished.push((probability * transition, extended));
+                } else {
+                    next.push((probability * transition, extended));
+                }
+            }
+
+            if !extended_any {
+                finished.push((probability, path));
+            }
+        }
+
+        next.sort_by(|lhs, rhs| rhs.0.total_cmp(&lhs.0));
+        next.truncate(count);
+        beam = next;
+    }
+
+    finished.extend(beam);
+    finished.retain(|(_, path)| !path.is_empty());
+    finished.sort_by(|lhs, rhs| rhs.0.total_cmp(&lhs.0));
+    finished.truncate(count);
+
+    finished
+        .into_iter()
+        .map(|(probability, path)| Path {
+            probability,
+            transcript: to_transcript(database, person, &path),
+        })
+        .collect()
+}
+
+// probability of each response to the phrase when said by the speaker,
+// responses whose condition does not hold for the speaker without any flags are skipped
+fn transitions(database: &Database, index: usize, speaker: &GeneralPerson) -> Vec<(usize, f32)> {
+    let phrase = &database.phrases[index];
+    let flags = BTreeSet::new();
+    let mut weights = BTreeMap::new();
+    for response in &phrase.responses {
+        if !phrase.allows(response.index, speaker, &flags) {
+            continue;
+        }
+        *weights.entry(response.index).or_insert(0.0) +=
+            response.count as f32 * f32::exp(-response.person.distance(speaker));
+    }
+
+    let total: f32 = weights.values().sum();
+    weights
+        .into_iter()
+        .map(|(response, weight)| (response, weight / total))
+        .collect()
+}
+
+fn to_transcript(database: &Database, person: &GeneralPerson, path: &[usize]) -> Transcript {
+    let mut transcript = Transcript::new();
+    for (turn, &index) in path.iter().enumerate() {
+        let phrase = &database.phrases[index];
+        transcript.push(Turn {
+            index,
+            text: phrase.texts[0].clone(),
+            template: None,
+            youtalk: person.youtalk != (turn % 2 == 1),
+            speaker: None,
+            kind: if phrase.ending.is_some() {
+                TurnKind::Ended
+            } else {
+                TurnKind::Chosen
+            },
+            recorded: false,
+            author: phrase.author(0).cloned(),
+            before_effects: None,
+        });
+    }
+    transcript
+}
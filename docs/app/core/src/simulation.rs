@@ -0,0 +1,119 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_derive::Serialize;
+
+use crate::chat::Chat;
+use crate::database::Database;
+use crate::transcript::Transcript;
+
+const SPEAKERS: [&str; 2] = ["first", "second"];
+
+// conversation between two npcs choosing random options
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Simulation {
+    seed: u64,
+    length: usize,
+    outcome: Option<String>,
+    // stopped without an outcome because no option was left
+    dead_end: bool,
+    // stopped by the turn limit
+    truncated: bool,
+    // turns repeating a phrase said earlier in the conversation
+    repeats: usize,
+    transcript: Transcript,
+}
+
+impl Simulation {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SimulationStats {
+    conversations: usize,
+    mean_length: f32,
+    max_length: usize,
+    dead_ends: usize,
+    truncated: usize,
+    // conversations that returned to an earlier phrase
+    loops: usize,
+    outcomes: BTreeMap<String, usize>,
+}
+
+impl SimulationStats {
+    pub fn new() -> Self {
+        SimulationStats::default()
+    }
+
+    pub fn add(&mut self, simulation: &Simulation) {
+        let total = self.mean_length * self.conversations as f32 + simulation.length as f32;
+        self.conversations += 1;
+        self.mean_length = total / self.conversations as f32;
+        self.max_length = self.max_length.max(simulation.length);
+        self.dead_ends += simulation.dead_end as usize;
+        self.truncated += simulation.truncated as usize;
+        self.loops += (simulation.repeats > 0) as usize;
+        if let Some(outcome) = &simulation.outcome {
+            *self.outcomes.entry(outcome.clone()).or_default() += 1;
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+// persons are given in the person description format, the first one opens
+// the conversation started in the context; nothing is recorded to the database,
+// fails on malformed persons
+pub fn simulate(
+    database: &mut Database,
+    first_description: &str,
+    second_description: &str,
+    context: &str,
+    max_turns: usize,
+    seed: u64,
+) -> Option<Simulation> {
+    let participants = format!(
+        r#"[{{"name": "{}", "you_talk": true, "person": {first_description}}},
+            {{"name": "{}", "you_talk": false, "person": {second_description}}}]"#,
+        SPEAKERS[0], SPEAKERS[1],
+    );
+    let mut chat = Chat::new_party(database, &participants)?;
+    let mut gen = ChaCha8Rng::seed_from_u64(seed);
+    chat.set_seed(gen.gen());
+    chat.enter(context);
+
+    let mut dead_end = false;
+    for turn in 0..max_turns {
+        chat.choose_speaker(SPEAKERS[turn % 2]);
+        let phrases = chat.get_phrases();
+        if phrases.is_empty() {
+            dead_end = chat.outcome().is_none();
+            break;
+        }
+        chat.choose_phrase_immutably(gen.gen_range(0..phrases.len()));
+    }
+
+    let transcript = chat.transcript().clone();
+    let mut said = BTreeSet::new();
+    let repeats = transcript
+        .turns
+        .iter()
+        .filter(|turn| !said.insert(turn.index))
+        .count();
+    let outcome = chat.outcome();
+
+    Some(Simulation {
+        seed,
+        length: transcript.turns.len(),
+        truncated: !dead_end && outcome.is_none() && transcript.turns.len() == max_turns,
+        outcome,
+        dead_end,
+        repeats,
+        transcript,
+    })
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+// phrase texts may contain placeholders like {player_name}, they are stored
+// in the database as is and filled with values known to a Chat when shown
+
+pub(crate) fn fill(text: &str, variables: &BTreeMap<String, String>) -> String {
+    let mut filled = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        filled += &rest[..start];
+        rest = &rest[start..];
+
+        let value = rest
+            .find('}')
+            .and_then(|end| variables.get(&rest[1..end]).map(|value| (end, value)));
+        if let Some((end, value)) = value {
+            filled += value;
+            rest = &rest[end + 1..];
+        } else {
+            filled.push('{');
+            rest = &rest[1..];
+        }
+    }
+
+    filled + rest
+}
+
+// replaces whole-word occurrences of known values with their placeholders
+// in a single pass over the text, the longest value wins where several match
+pub(crate) fn templatize(text: &str, variables: &BTreeMap<String, String>) -> String {
+    let mut template = String::new();
+    let mut position = 0;
+
+    while let Some(next) = text[position..].chars().next() {
+        let is_word_start = !text[..position]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphanumeric);
+        let found = variables
+            .iter()
+            .filter(|(_, value)| {
+                let end = position + value.len();
+                is_word_start
+                    && !value.is_empty()
+                    && text[position..].starts_with(value.as_str())
+                    && !text[end..].chars().next().is_some_and(char::is_alphanumeric)
+            })
+            .fold(None, |longest: Option<(&String, &String)>, (name, value)| {
+                match longest {
+                    Some((_, kept)) if kept.len() >= value.len() => longest,
+                    _ => Some((name, value)),
+                }
+            });
+
+        if let Some((name, value)) = found {
+            template.push('{');
+            template += name;
+            template.push('}');
+            position += value.len();
+        } else {
+            template.push(next);
+            position += next.len_utf8();
+        }
+    }
+
+    template
+}
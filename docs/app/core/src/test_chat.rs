@@ -0,0 +1,820 @@
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::chat::{Chat, ChatState, Fallback};
+use crate::condition::Condition;
+use crate::database::{Database, SERVER};
+use crate::simulation::{simulate, SimulationStats};
+use crate::template::{fill, templatize};
+use crate::test_database::generate_person;
+use crate::transcript::{Turn, TurnKind};
+
+fn say(database: &mut Database, person: &str, text: &str) {
+    let mut chat = Chat::new(database, true, person);
+    chat.start("");
+    chat.add_phrase(text);
+}
+
+#[test]
+fn test_chat_suggestions() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    say(&mut database, &person, "Hello!");
+    say(&mut database, &person, "hello");
+    say(&mut database, &person, "Good morning.");
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    let mut suggestions = chat.get_suggestions();
+    suggestions.sort_by_key(|suggestion| suggestion.contributors);
+
+    assert_eq!(suggestions.len(), 2);
+    assert_eq!(suggestions[0].text, "Good morning.");
+    assert_eq!(suggestions[0].contributors, 1);
+    assert_eq!(suggestions[1].contributors, 2);
+    assert_eq!(suggestions[1].personas.len(), 1);
+    assert!(["Hello!", "hello"].contains(&suggestions[1].text.as_str()));
+    assert!((suggestions[0].probability + suggestions[1].probability - 1.0).abs() < 1e-5);
+    assert!(suggestions[1].probability > suggestions[0].probability);
+}
+
+#[test]
+fn test_chat_transcript() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Hello!");
+    chat.add_phrase("Hi, how are you?");
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    let phrases = chat.get_phrases();
+    chat.choose_phrase(0);
+    let answers = chat.get_phrases();
+    chat.choose_phrase_immutably(0);
+
+    let turns = &chat.transcript().turns;
+    assert_eq!(turns.len(), 2);
+    assert_eq!(turns[0].text, phrases[0]);
+    assert_eq!(turns[0].kind, TurnKind::Chosen);
+    assert!(turns[0].youtalk);
+    assert_eq!(turns[1].text, answers[0]);
+    assert!(!turns[1].youtalk);
+
+    assert_eq!(
+        chat.transcript().to_markdown(),
+        "- **You:** Hello!\n- **NPC:** Hi, how are you?\n"
+    );
+    let json: Vec<Turn> = serde_json::from_str(&chat.transcript().to_json()).unwrap();
+    assert_eq!(&json, turns);
+}
+
+#[test]
+fn test_chat_undo() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Hello!");
+    chat.add_phrase("Hi, how are you?");
+    database.updated(SERVER);
+    let synced = database.clone();
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    chat.add_phrase("Go away.");
+    assert_eq!(database.size(), 4);
+
+    assert!(chat.undo());
+    assert!(chat.undo());
+    assert!(!chat.undo());
+    assert_eq!(database, synced);
+    assert_eq!(database.difference(SERVER), Database::new());
+
+    // synced turns rewind the chat but stay in the database
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    database.updated(SERVER);
+    assert!(!chat.undo());
+    assert_eq!(database.size(), 3);
+    assert_eq!(chat.get_phrases(), vec!["Hello!".to_string()]);
+
+    // a phrase followed by newer ones keeps its only text
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Farewell.");
+    let mut other = Chat::new(&mut database, true, &person);
+    other.start("tavern");
+    other.add_phrase("Later.");
+    assert!(chat.undo());
+    assert_eq!(database.phrases[database.get_index("Farewell.").unwrap()].texts.len(), 1);
+    assert!(database.validate().is_valid());
+}
+
+#[test]
+fn test_chat_state() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    for text in ["Hello!", "Good morning.", "Greetings."] {
+        let mut chat = Chat::new(&mut database, true, &person);
+        chat.start("");
+        chat.add_phrase(text);
+        chat.add_phrase("Hi, how are you?");
+    }
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.get_phrases();
+    chat.choose_phrase_immutably(1);
+    let state = serde_json::to_string(&chat.state()).unwrap();
+    let state: ChatState = serde_json::from_str(&state).unwrap();
+
+    // indices of a reloaded database differ from the original ones
+    let mut reloaded = Database::new();
+    reloaded.updated(SERVER);
+    reloaded.insert_texts_at("Unrelated", vec!["Unrelated".to_string()]);
+    reloaded.merge(database.total_clone());
+
+    let mut restored = Chat::restore(&mut reloaded, state).unwrap();
+    assert_eq!(
+        restored.transcript().to_markdown(),
+        chat.transcript().to_markdown()
+    );
+    assert_eq!(restored.get_phrases(), chat.get_phrases());
+    assert_eq!(restored.get_phrases(), vec!["Hi, how are you?".to_string()]);
+}
+
+#[test]
+fn test_chat_history() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    for (greeting, answer) in [("Hello!", "Fine."), ("Good evening.", "Tired.")] {
+        for _ in 0..2 {
+            let mut chat = Chat::new(&mut database, true, &person);
+            chat.start("");
+            chat.set_history_length(2);
+            chat.add_phrase(greeting);
+            chat.add_phrase("How are you?");
+            chat.add_phrase(answer);
+        }
+    }
+
+    let question = &database.phrases[database.get_index("How are you?").unwrap()];
+    assert_eq!(question.history_responses.len(), 2);
+    assert!(question.history_responses.iter().all(|response| response.count == 2));
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.set_history_length(2);
+    chat.add_phrase("Hello!");
+    chat.add_phrase("How are you?");
+    for _ in 0..10 {
+        assert_eq!(chat.get_phrases(), vec!["Fine.".to_string()]);
+    }
+
+    // without enough data for the history, chat backs off to the last phrase
+    chat.undo();
+    chat.undo();
+    chat.add_phrase("Hey.");
+    chat.add_phrase("How are you?");
+    assert_eq!(chat.get_phrases().len(), 2);
+
+    chat.set_history_length(1);
+    chat.undo();
+    chat.undo();
+    chat.add_phrase("Hello!");
+    chat.add_phrase("How are you?");
+    assert_eq!(chat.get_phrases().len(), 2);
+
+    // history responses are filtered by situation as well
+    for _ in 0..2 {
+        let mut chat = Chat::new(&mut database, true, &person);
+        chat.start("");
+        chat.set_history_length(2);
+        chat.set_situation(r#"{"location": "harbor"}"#);
+        chat.add_phrase("Hello!");
+        chat.add_phrase("How are you?");
+        chat.add_phrase("Soaked.");
+    }
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.set_history_length(2);
+    chat.set_situation(r#"{"location": "harbor"}"#);
+    chat.set_situation_filter(true);
+    chat.add_phrase("Hello!");
+    chat.add_phrase("How are you?");
+    assert_eq!(chat.get_phrases(), vec!["Soaked.".to_string()]);
+}
+
+#[test]
+fn test_chat_ending() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Hello!");
+    chat.add_phrase("Goodbye.");
+    chat.add_phrase("See you.");
+    assert_eq!(chat.outcome(), None);
+    chat.undo();
+    chat.end_conversation("farewell");
+    assert_eq!(chat.outcome(), Some("farewell".to_string()));
+    assert!(chat.get_phrases().is_empty());
+
+    assert!(database.mark_ending("goodbye", "farewell"));
+    let mut server = Database::new();
+    server.merge(database.difference(SERVER));
+    assert_eq!(server, database);
+
+    let mut chat = Chat::new(&mut server, false, &person);
+    chat.add_phrase("Hello!");
+    let suggestions = chat.get_suggestions();
+    assert_eq!(suggestions.len(), 1);
+    assert_eq!(suggestions[0].ending, Some("farewell".to_string()));
+    chat.choose_phrase(0);
+    assert_eq!(chat.outcome(), Some("farewell".to_string()));
+
+    let suggestions = Chat::new(&mut server, true, &person).get_suggestions();
+    assert_eq!(suggestions[0].text, "Hello!");
+    assert_eq!(suggestions[0].ending, None);
+}
+
+#[test]
+fn test_chat_fallbacks() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Hello!");
+    chat.add_phrase("How is the harvest?");
+    chat.add_phrase("The harvest is poor.");
+    chat.add_phrase("Nice weather.");
+    assert!(database.mark_generic("nice weather"));
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.add_phrase("Where is the harvest?");
+    chat.add_phrase("Unknown.");
+    assert!(chat.get_suggestions().is_empty());
+    assert_eq!(chat.fallback(), None);
+
+    chat.undo();
+    chat.set_fallbacks(vec![Fallback::Similar, Fallback::Generic]);
+    let suggestions = chat.get_suggestions();
+    assert_eq!(suggestions[0].text, "The harvest is poor.");
+    assert_eq!(suggestions[0].fallback, Some(Fallback::Similar));
+
+    chat.add_phrase("Unknown.");
+    let suggestions = chat.get_suggestions();
+    assert_eq!(suggestions[0].text, "Nice weather.");
+    assert_eq!(suggestions[0].contributors, 1);
+    assert_eq!(suggestions[0].personas.len(), 1);
+    assert_eq!(chat.fallback(), Some(Fallback::Generic));
+
+    chat.set_fallbacks(vec![Fallback::Restart]);
+    let phrases = chat.get_phrases();
+    assert_eq!(phrases.len(), 2);
+    assert!(phrases.contains(&"Hello!".to_string()));
+    assert_eq!(chat.fallback(), Some(Fallback::Restart));
+}
+
+#[test]
+fn test_chat_party() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let participants = format!(
+        r#"[{{"name": "player", "you_talk": true, "person": {}}},
+            {{"name": "merchant", "you_talk": false, "person": {}}},
+            {{"name": "guard", "you_talk": false, "person": {}}}]"#,
+        generate_person(&mut rng),
+        generate_person(&mut rng),
+        generate_person(&mut rng)
+    );
+    let mut database = Database::new();
+
+    let mut chat = Chat::new_party(&mut database, &participants).unwrap();
+    chat.start("");
+    chat.add_phrase("Good day.");
+    assert!(chat.choose_speaker("merchant"));
+    chat.add_phrase("Fresh fish!");
+    assert!(!chat.choose_speaker("priest"));
+    chat.add_phrase("Best in town!");
+    assert!(chat.choose_speaker("guard"));
+    chat.add_phrase("Move along.");
+    assert!(chat.undo());
+
+    assert_eq!(
+        chat.transcript().to_markdown(),
+        "- **player:** Good day. _(typed)_\n\
+         - **merchant:** Fresh fish! _(typed)_\n\
+         - **merchant:** Best in town! _(typed)_\n"
+    );
+
+    let index = database.get_index("Good day.").unwrap();
+    let response = &database.phrases[index].responses[0];
+    assert_eq!(response.index, database.get_index("Fresh fish!").unwrap());
+    assert_eq!(response.person.role.as_deref(), Some("merchant"));
+    assert!(!response.person.youtalk);
+    assert!(database.get_index("Move along.").is_none());
+}
+
+#[test]
+fn test_chat_effects() {
+    let person = r#"{"job": "Farmer", "character": {"rebellion": 1, "fear_propension": 2, "popularity": 0, "animosity": 0, "political_agreement": 0, "fear": 3}}"#;
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    let mut chat = Chat::new(&mut database, true, person);
+    chat.start("");
+    chat.add_phrase("Give me your money!");
+    let effects = serde_json::from_str(
+        r#"[{"trait": "fear", "delta": 2}, {"trait": "animosity", "delta": -1}]"#,
+    )
+    .unwrap();
+    assert!(database.set_effects("give me your money", effects));
+
+    let mut server = Database::new();
+    server.merge(database.difference(SERVER));
+    assert_eq!(server, database);
+
+    let mut chat = Chat::new(&mut server, true, person);
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    let changed: serde_json::Value = serde_json::from_str(&chat.person_description()).unwrap();
+    assert_eq!(changed["character"]["fear"], 5);
+    assert_eq!(changed["character"]["animosity"], -1);
+
+    chat.undo();
+    let reverted: serde_json::Value = serde_json::from_str(&chat.person_description()).unwrap();
+    let original: serde_json::Value = serde_json::from_str(person).unwrap();
+    assert_eq!(reverted, original);
+
+    // clamped traits are restored too
+    let effects = serde_json::from_str(r#"[{"trait": "fear", "delta": 127}]"#).unwrap();
+    assert!(server.set_effects("give me your money", effects));
+    let mut chat = Chat::new(&mut server, true, person);
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    let changed: serde_json::Value = serde_json::from_str(&chat.person_description()).unwrap();
+    assert_eq!(changed["character"]["fear"], 127);
+    chat.undo();
+    let reverted: serde_json::Value = serde_json::from_str(&chat.person_description()).unwrap();
+    assert_eq!(reverted, original);
+
+    // clearing the effects is synced as well
+    database.updated(SERVER);
+    assert!(database.set_effects("give me your money", Vec::new()));
+    server.merge(database.difference(SERVER));
+    let index = server.get_index("give me your money").unwrap();
+    assert_eq!(server.phrases[index].effects, Some(Vec::new()));
+}
+
+#[test]
+fn test_chat_effects_change_sampling() {
+    let calm = r#"{"job": "Farmer", "character": {"rebellion": 0, "fear_propension": 0, "popularity": 0, "animosity": 0, "political_agreement": 0, "fear": -5}}"#;
+    let scared = r#"{"job": "Farmer", "character": {"rebellion": 0, "fear_propension": 0, "popularity": 0, "animosity": 0, "political_agreement": 0, "fear": 5}}"#;
+    let mut database = Database::new();
+
+    for (person, answer) in [(calm, "Sure, come in."), (scared, "Please, leave me alone!")] {
+        let mut chat = Chat::new(&mut database, true, person);
+        chat.start("");
+        chat.add_phrase("Hello.");
+        chat.add_phrase(answer);
+    }
+    let effects = serde_json::from_str(r#"[{"trait": "fear", "delta": 10}]"#).unwrap();
+    database.set_effects("hello", effects);
+
+    let mut chat = Chat::new(&mut database, true, calm);
+    chat.add_phrase("Hello.");
+    let suggestions = chat.get_suggestions();
+    let scared_answer = suggestions
+        .iter()
+        .find(|suggestion| suggestion.text == "Please, leave me alone!")
+        .unwrap();
+    assert!(scared_answer.probability > 0.5);
+}
+
+#[test]
+fn test_condition_parsing() {
+    for valid in [
+        "popularity > 5",
+        "job in [Noble, Priest] and popularity >= -2",
+        "not (flag night or job == Farmer) and fear_propension != 0",
+    ] {
+        assert!(Condition::from_str(valid).is_ok(), "{}", valid);
+    }
+
+    for invalid in ["popularity >", "job in [Noble", "charm > 5", "fear > 5 and", "fear ~ 5"] {
+        assert!(Condition::from_str(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_chat_conditions() {
+    let noble = r#"{"job": "Noble", "character": {"rebellion": 0, "fear_propension": 0, "popularity": 7, "animosity": 0, "political_agreement": 0, "fear": 0}}"#;
+    let farmer = r#"{"job": "Farmer", "character": {"rebellion": 0, "fear_propension": 0, "popularity": 7, "animosity": 0, "political_agreement": 0, "fear": 0}}"#;
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    for answer in ["Bow before me.", "Good evening."] {
+        let mut chat = Chat::new(&mut database, true, noble);
+        chat.start("");
+        chat.add_phrase(answer);
+    }
+    let condition = "job in [Noble] and popularity > 5 and not flag night";
+    assert!(!database.set_condition("", "Bow before me.", "popularity >"));
+    assert!(database.set_condition("", "Bow before me.", condition));
+
+    let mut server = Database::new();
+    server.merge(database.difference(SERVER));
+    server.updated("client");
+    assert_eq!(server, database);
+
+    for (person, night, count) in [(noble, false, 2), (noble, true, 1), (farmer, false, 1)] {
+        let mut chat = Chat::new(&mut server, true, person);
+        chat.set_flag("night", night);
+        assert_eq!(chat.get_phrases().len(), count);
+    }
+
+    assert!(server.set_condition("", "Bow before me.", ""));
+    database.merge(server.difference("client"));
+    assert_eq!(Chat::new(&mut database, true, farmer).get_phrases().len(), 2);
+}
+
+#[test]
+fn test_chat_fallback_conditions() {
+    let noble = r#"{"job": "Noble", "character": {"rebellion": 0, "fear_propension": 0, "popularity": 7, "animosity": 0, "political_agreement": 0, "fear": 0}}"#;
+    let farmer = r#"{"job": "Farmer", "character": {"rebellion": 0, "fear_propension": 0, "popularity": 7, "animosity": 0, "political_agreement": 0, "fear": 0}}"#;
+    let mut database = Database::new();
+    for answer in ["Bow before me.", "Good evening."] {
+        say(&mut database, noble, answer);
+    }
+    assert!(database.set_condition("", "Bow before me.", "job == Noble"));
+    say(&mut database, farmer, "Hmm.");
+
+    let noble_phrases = vec!["Bow before me.", "Good evening.", "Hmm."];
+    for (person, phrases) in [(noble, noble_phrases), (farmer, vec!["Good evening.", "Hmm."])] {
+        let mut chat = Chat::new(&mut database, true, person);
+        chat.set_fallbacks(vec![Fallback::Restart]);
+        chat.start("");
+        chat.add_phrase("Hmm.");
+        let mut suggested = chat.get_phrases();
+        suggested.sort();
+        assert_eq!(suggested, phrases);
+        assert_eq!(chat.fallback(), Some(Fallback::Restart));
+    }
+
+    assert!(database.mark_generic("bow before me"));
+    let mut chat = Chat::new(&mut database, true, farmer);
+    chat.set_fallbacks(vec![Fallback::Generic]);
+    chat.start("");
+    chat.add_phrase("Hmm.");
+    assert!(chat.get_phrases().is_empty());
+    assert_eq!(chat.fallback(), None);
+}
+
+#[test]
+fn test_template() {
+    let variables = BTreeMap::from([
+        ("player_name".to_string(), "Al".to_string()),
+        ("job".to_string(), "fisherman".to_string()),
+    ]);
+
+    assert_eq!(
+        fill("Hi {player_name}, a fine day for a {job} {unknown}{", &variables),
+        "Hi Al, a fine day for a fisherman {unknown}{"
+    );
+    assert_eq!(
+        templatize("Al, Alan and Al's fisherman", &variables),
+        "{player_name}, Alan and {player_name}'s {job}"
+    );
+
+    // values are not looked up inside placeholders already inserted
+    let variables = BTreeMap::from([
+        ("a_name".to_string(), "Bob".to_string()),
+        ("z".to_string(), "name".to_string()),
+        ("full".to_string(), "Bob Smith".to_string()),
+    ]);
+    assert_eq!(
+        templatize("Bob Smith, Bob and name", &variables),
+        "{full}, {a_name} and {z}"
+    );
+}
+
+#[test]
+fn test_chat_template_variables() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, false, &person);
+    chat.start("");
+    chat.set_variable("player_name", "Alice");
+    chat.add_phrase("Good morning, Alice!");
+    assert_eq!(chat.transcript().turns[0].text, "Good morning, Alice!");
+
+    let mut chat = Chat::new(&mut database, false, &person);
+    chat.set_variable("player_name", "Bob");
+    chat.add_phrase("Good morning, Bob!");
+    assert_eq!(database.phrases.len(), 2);
+
+    let mut chat = Chat::new(&mut database, false, &person);
+    chat.set_variable("player_name", "Carol");
+    assert_eq!(chat.get_phrases(), vec!["Good morning, Carol!".to_string()]);
+    chat.choose_phrase(0);
+    assert_eq!(
+        chat.transcript().turns[0].stored_text(),
+        "Good morning, {player_name}!"
+    );
+
+    let state = chat.state();
+    let restored = Chat::restore(&mut database, state).unwrap();
+    assert_eq!(restored.transcript(), chat.transcript());
+}
+
+#[test]
+fn test_chat_start_context() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Hello.");
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("Halt!");
+    assert!(database.set_condition("", "Halt!", "flag night"));
+
+    // borrowed openers keep the conditions of the general start phrase
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("tavern");
+    assert_eq!(chat.get_phrases(), vec!["Hello.".to_string()]);
+    chat.add_phrase("Another ale?");
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("tavern");
+    assert_eq!(chat.get_phrases(), vec!["Another ale?".to_string()]);
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    assert_eq!(chat.get_phrases(), vec!["Hello.".to_string()]);
+}
+
+#[test]
+fn test_chat_situations() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    for (situation, answer) in [
+        (r#"{"location": "harbor", "tags": ["rain"]}"#, "Wet day, huh?"),
+        (r#"{"location": "market", "weather": "sunny"}"#, "Fresh apples!"),
+    ] {
+        let mut chat = Chat::new(&mut database, true, &person);
+        chat.start("");
+        assert!(chat.set_situation(situation));
+        chat.add_phrase(answer);
+    }
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    assert!(!chat.set_situation("{\"tags\": 3}"));
+    chat.set_situation(r#"{"scenario": "siege"}"#);
+    chat.add_phrase("To the walls!");
+    assert!(chat.undo());
+    let situated = database.phrases[0]
+        .responses
+        .iter()
+        .filter(|response| !response.situation.is_empty())
+        .count();
+    assert_eq!(situated, 2);
+
+    let mut server = Database::new();
+    server.merge(database.difference(SERVER));
+    server.updated("client");
+    assert_eq!(server, database);
+
+    let mut chat = Chat::new(&mut server, true, &person);
+    chat.start("");
+    chat.set_situation(r#"{"location": "harbor"}"#);
+    let suggestions = chat.get_suggestions();
+    let harbor = suggestions
+        .iter()
+        .find(|suggestion| suggestion.text == "Wet day, huh?")
+        .unwrap();
+    assert!((harbor.probability - 0.75).abs() < 1e-5);
+
+    chat.set_situation_filter(true);
+    chat.set_situation(r#"{"weather": "sunny"}"#);
+    assert_eq!(chat.get_phrases(), vec!["Fresh apples!".to_string()]);
+}
+
+#[test]
+fn test_chat_text_authors() {
+    let noble = r#"{"job": "Noble", "character": {"rebellion": 5, "fear_propension": 5, "popularity": 5, "animosity": 5, "political_agreement": 5, "fear": 5}}"#;
+    let fisherman = r#"{"job": "Fisherman", "character": {"rebellion": -5, "fear_propension": -5, "popularity": -5, "animosity": -5, "political_agreement": -5, "fear": -5}}"#;
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    say(&mut database, noble, "Good day to you, sir.");
+    say(&mut database, fisherman, "good day to you sir");
+    assert_eq!(database.phrases[1].authors.len(), 2);
+
+    let mut server = Database::new();
+    server.merge(database.difference(SERVER));
+    server.updated("client");
+    assert_eq!(server, database);
+
+    for (person, text) in [(noble, "Good day to you, sir."), (fisherman, "good day to you sir")] {
+        let mut chat = Chat::new(&mut server, true, person);
+        chat.start("");
+        let matching = (0..200)
+            .filter(|_| chat.get_phrases() == vec![text.to_string()])
+            .count();
+        assert!(matching > 150);
+    }
+}
+
+#[test]
+fn test_chat_voice() {
+    let guard = |job: &str, trait_value: i8| {
+        format!(
+            r#"[{{"name": "Guard", "you_talk": false, "person": {{"job": "{job}", "character": {{"rebellion": {trait_value}, "fear_propension": {trait_value}, "popularity": {trait_value}, "animosity": {trait_value}, "political_agreement": {trait_value}, "fear": {trait_value}}}}}}}]"#
+        )
+    };
+    let mut database = Database::new();
+
+    for (job, trait_value, texts) in [
+        ("Noble", 5, ["Halt!", "Who goes there?"]),
+        ("Fisherman", -5, ["halt", "who goes there"]),
+    ] {
+        let mut chat = Chat::new_party(&mut database, &guard(job, trait_value)).unwrap();
+        chat.start("");
+        for text in texts {
+            chat.add_phrase(text);
+        }
+    }
+
+    for _ in 0..20 {
+        let mut chat = Chat::new_party(&mut database, &guard("Priest", 0)).unwrap();
+        chat.start("");
+        chat.set_voice_strength(5.0);
+        chat.get_phrases();
+        chat.choose_phrase_immutably(0);
+        chat.get_phrases();
+        chat.choose_phrase_immutably(0);
+
+        let turns = &chat.transcript().turns;
+        assert!(turns[0].author.is_some());
+        assert_eq!(turns[0].author, turns[1].author);
+    }
+}
+
+#[test]
+fn test_chat_goals() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    for texts in [
+        ["Hello.", "Nice weather.", "Indeed."],
+        ["Hello.", "Any news?", "Could you help with the harvest?"],
+    ] {
+        let mut chat = Chat::new(&mut database, true, &person);
+        chat.start("");
+        for text in texts {
+            chat.add_phrase(text);
+        }
+    }
+    let goal = "Could you help with the harvest?";
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    assert!(!chat.set_goals(r#"["Help me!"]"#));
+    assert!(chat.set_goals(&format!(r#"["{goal}"]"#)));
+    chat.set_goal_strength(10.0);
+    chat.get_phrases();
+    chat.choose_phrase_immutably(0);
+
+    let suggestions = chat.get_suggestions();
+    let news = suggestions
+        .iter()
+        .find(|suggestion| suggestion.text == "Any news?")
+        .unwrap();
+    assert!(news.probability > 0.99);
+
+    chat.set_goal_strength(0.0);
+    let suggestions = chat.get_suggestions();
+    assert!(suggestions
+        .iter()
+        .all(|suggestion| (suggestion.probability - 0.5).abs() < 1e-5));
+
+    // cached distances follow new responses
+    let mut other = Chat::new(&mut database, true, &person);
+    other.start("");
+    for text in ["Hello.", "Nice weather.", goal] {
+        other.add_phrase(text);
+    }
+    chat.set_goal_strength(10.0);
+    let suggestions = chat.get_suggestions();
+    let weather = suggestions
+        .iter()
+        .find(|suggestion| suggestion.text == "Nice weather.")
+        .unwrap();
+    assert!((weather.probability - 2.0 / 3.0).abs() < 1e-5);
+}
+
+#[test]
+fn test_simulation() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let first = generate_person(&mut rng);
+    let second = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, true, &first);
+    chat.start("");
+    for text in ["Hello.", "Hi.", "Hello.", "Hi."] {
+        chat.add_phrase(text);
+    }
+    let mut chat = Chat::new(&mut database, true, &first);
+    chat.start("");
+    chat.add_phrase("Hello.");
+    chat.end_conversation("farewell");
+    let size = database.size();
+    let unchanged = database.clone();
+
+    assert!(simulate(&mut database, &first, "{}", "", 10, 0).is_none());
+    let simulation = simulate(&mut database, &first, &second, "", 10, 3).unwrap();
+    assert_eq!(simulation, simulate(&mut database, &first, &second, "", 10, 3).unwrap());
+    assert_eq!(database.size(), size);
+    assert!(simulate(&mut database, &first, &second, "tavern", 10, 3).is_some());
+    assert_eq!(database, unchanged);
+
+    let mut stats = SimulationStats::new();
+    for seed in 0..20 {
+        stats.add(&simulate(&mut database, &first, &second, "", 10, seed).unwrap());
+    }
+    let stats: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+    assert_eq!(stats["conversations"], 20);
+    let outcomes = stats["outcomes"]["farewell"].as_u64().unwrap_or(0);
+    let truncated = stats["truncated"].as_u64().unwrap();
+    let dead_ends = stats["dead_ends"].as_u64().unwrap();
+    assert_eq!(outcomes + truncated + dead_ends, 20);
+    assert!(truncated > 0 && outcomes > 0);
+    assert!(stats["loops"].as_u64().unwrap() >= truncated);
+}
+
+#[test]
+fn test_chat_repetition_penalty() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    for text in ["How are you?", "Fine, and you?", "How are you?"] {
+        chat.add_phrase(text);
+    }
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    for text in ["How are you?", "Fine, and you?", "Goodbye."] {
+        chat.add_phrase(text);
+    }
+
+    let probability = |chat: &mut Chat| {
+        chat.get_suggestions()
+            .into_iter()
+            .find(|suggestion| suggestion.text == "How are you?")
+            .unwrap()
+            .probability
+    };
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.get_phrases();
+    chat.choose_phrase_immutably(0);
+    chat.get_phrases();
+    chat.choose_phrase_immutably(0);
+
+    chat.set_repetition_penalty(0.0, 6);
+    assert!((probability(&mut chat) - 0.5).abs() < 1e-5);
+    chat.set_repetition_penalty(2.0, 6);
+    assert!((probability(&mut chat) - 1.0 / (1.0 + f32::exp(2.0))).abs() < 1e-5);
+    chat.set_repetition_penalty(2.0, 1);
+    assert!((probability(&mut chat) - 0.5).abs() < 1e-5);
+}
@@ -4,9 +4,13 @@ use std::collections::HashSet;
 use std::iter::zip;
 use std::str::FromStr;
 
+use crate::bake::bake;
 use crate::chat::Chat;
-use crate::data::WordCloud;
+use crate::data::{GeneralPerson, Phrase, Response, Situation, Trait, WordCloud};
 use crate::database::{Database, SERVER};
+use crate::graph::{cycles, report};
+use crate::paths::best_paths;
+use crate::transcript::TurnKind;
 
 #[test]
 fn test_wordcloud() {
@@ -22,13 +26,23 @@ fn test_wordcloud() {
 }
 
 fn initialize_chat(database: &mut Database, rng: &mut ChaCha8Rng) -> Chat {
-    let person = format!(
-        r#"{{"job": "{}", "character": {{"hostile": {}, "rebellious": {}}}}}"#,
+    let person = generate_person(rng);
+    let mut chat = Chat::new(database, rng.gen_bool(0.5), &person);
+    chat.start("");
+    chat
+}
+
+pub(crate) fn generate_person(rng: &mut ChaCha8Rng) -> String {
+    format!(
+        r#"{{"job": "{}", "character": {{"rebellion": {}, "fear_propension": {}, "popularity": {}, "animosity": {}, "political_agreement": {}, "fear": {}}}}}"#,
         ["Farmer", "Merchant", "Priest"][rng.gen_range(0..3)],
+        rng.gen_range(1..=5),
+        rng.gen_range(-5..=5),
+        rng.gen_range(-5..=5),
+        rng.gen_range(-5..=5),
         rng.gen_range(-5..=5),
         rng.gen_range(-5..=5)
-    );
-    Chat::new(database, rng.gen_bool(0.5), &person)
+    )
 }
 
 fn generate_words(rng: &mut ChaCha8Rng) -> Vec<String> {
@@ -46,7 +60,7 @@ fn generate_words(rng: &mut ChaCha8Rng) -> Vec<String> {
     words
 }
 
-fn generate_text(words: &Vec<String>, rng: &mut ChaCha8Rng) -> String {
+fn generate_text(words: &[String], rng: &mut ChaCha8Rng) -> String {
     let mut text = String::new();
     let text_length = rng.gen_range(1..=4);
 
@@ -58,13 +72,28 @@ fn generate_text(words: &Vec<String>, rng: &mut ChaCha8Rng) -> String {
     text
 }
 
-fn client_chat(client: &mut Database, rng: &mut ChaCha8Rng, words: &Vec<String>) -> Database {
+// checks the incoming-edge index against one rebuilt from scratch
+fn assert_preceding_indexed(database: &Database) {
+    let reloaded = Database::from_str(&database.to_string()).unwrap();
+    for index in 0..database.phrases.len() {
+        assert_eq!(database.preceding(index), reloaded.preceding(index));
+    }
+}
+
+fn client_chat(client: &mut Database, rng: &mut ChaCha8Rng, words: &[String]) -> Database {
     let mut chat = initialize_chat(client, rng);
+    chat.set_history_length(rng.gen_range(1..=3));
     let chat_length = rng.gen_range(5..20);
 
     for _ in 0..chat_length {
         let phrases = chat.get_phrases();
-        if rng.gen_bool(1.0 / (1.0 + phrases.len() as f64)) {
+        if chat.outcome().is_some() {
+            break;
+        }
+
+        if rng.gen_bool(0.05) {
+            chat.end_conversation(["farewell", "deal"][rng.gen_range(0..2)]);
+        } else if rng.gen_bool(1.0 / (1.0 + phrases.len() as f64)) {
             chat.add_phrase(&generate_text(words, rng))
         } else {
             chat.choose_phrase(rng.gen_range(0..phrases.len()))
@@ -137,6 +166,9 @@ fn test_database_merge_concurrent() {
         server.updated(&ip);
 
         assert_eq!(client, &mut server);
+        assert_preceding_indexed(client);
+        assert_preceding_indexed(&server);
+        assert!(server.validate().is_valid());
     }
 
     // final update
@@ -147,3 +179,477 @@ fn test_database_merge_concurrent() {
         assert_eq!(client, server);
     }
 }
+
+const PRIEST: &str = r#"{"job": "Priest", "character": {"rebellion": 1, "fear_propension": 1, "popularity": 1, "animosity": 1, "political_agreement": 1, "fear": 1}}"#;
+
+// small village conversation recorded by a priest and a farmer
+fn village_database() -> Database {
+    let priest = PRIEST;
+    let farmer = r#"{"job": "Farmer", "character": {"rebellion": -1, "fear_propension": -1, "popularity": -1, "animosity": -1, "political_agreement": -1, "fear": -1}}"#;
+    let mut database = Database::new();
+
+    for (person, texts) in [
+        (priest, ["Bless you.", "Thank you, father.", "Go in peace."]),
+        (farmer, ["Good harvest?", "Thank you, father.", "Bless you."]),
+    ] {
+        let mut chat = Chat::new(&mut database, true, person);
+        chat.start("");
+        for text in texts {
+            chat.add_phrase(text);
+        }
+    }
+    let mut chat = Chat::new(&mut database, true, priest);
+    chat.start("");
+    chat.add_phrase("Good harvest?");
+    chat.end_conversation("farewell");
+    database
+}
+
+#[test]
+fn test_best_paths() {
+    let database = village_database();
+    let priest = PRIEST;
+    let person = GeneralPerson::new(serde_json::from_str(priest).unwrap(), true);
+    assert!(best_paths(&database, &person, "tavern", 5, 3).is_empty());
+
+    let paths = best_paths(&database, &person, "", 5, 3);
+    assert_eq!(paths.len(), 3);
+    let texts: Vec<&str> = paths[0]
+        .transcript
+        .turns
+        .iter()
+        .map(|turn| turn.text.as_str())
+        .collect();
+    assert_eq!(texts, vec!["Good harvest?", "<farewell>"]);
+    assert_eq!(paths[0].transcript.turns[1].kind, TurnKind::Ended);
+    assert_eq!(paths[1].transcript.turns[0].text, "Bless you.");
+    for (better, worse) in zip(&paths, &paths[1..]) {
+        assert!(better.probability >= worse.probability);
+    }
+    for path in &paths {
+        let visited: HashSet<usize> = path.transcript.turns.iter().map(|turn| turn.index).collect();
+        assert_eq!(visited.len(), path.transcript.turns.len());
+        assert!(path.transcript.turns.len() <= 5);
+    }
+
+    let mut database = database;
+    assert!(database.set_condition("", "Good harvest?", "job == Farmer"));
+    let paths = best_paths(&database, &person, "", 5, 3);
+    assert_eq!(paths[0].transcript.turns[0].text, "Bless you.");
+    assert!(paths
+        .iter()
+        .all(|path| path.transcript.turns[0].text != "Good harvest?"));
+}
+
+#[test]
+fn test_bake() {
+    let database = village_database();
+    let person = GeneralPerson::new(serde_json::from_str(PRIEST).unwrap(), true);
+
+    let dialogue = bake(&database, &person, "", 3, 1);
+    assert_eq!(dialogue, bake(&database, &person, "", 3, 1));
+    let contents: Vec<&str> = dialogue
+        .messages
+        .iter()
+        .map(|message| message.possibilities[0].contents.as_str())
+        .collect();
+    assert_eq!(contents, vec!["Good harvest?", "<farewell>"]);
+    assert_eq!(dialogue.messages[0].possibilities[0].options, vec![1]);
+    // farmer and priest are the low worker and religious categories of the game
+    let line = &dialogue.messages[0].possibilities[0];
+    assert_eq!(line.categories, vec![3, 4]);
+    let parameters = serde_json::to_value(&line.parameters).unwrap();
+    assert_eq!(parameters["rebellion"], 8.0);
+    for value in parameters.as_object().unwrap().values() {
+        let value = value.as_f64().unwrap();
+        assert!(value == 8.0 || (-1.0..=1.0).contains(&value));
+    }
+    let parameters = serde_json::to_value(&dialogue.messages[1].possibilities[0].parameters);
+    let parameters = parameters.unwrap();
+    assert!((parameters["popularity"].as_f64().unwrap() - 0.55).abs() < 1e-5);
+    assert!((parameters["fear"].as_f64().unwrap() - 0.1).abs() < 1e-5);
+    assert!(dialogue.messages[1].possibilities[0].options.is_empty());
+
+    let dialogue = bake(&database, &person, "", 4, 2);
+    for (id, message) in dialogue.messages.iter().enumerate() {
+        assert_eq!(message.id, id);
+        assert!(message.possibilities.len() <= 2);
+    }
+    let json = serde_json::to_string(&dialogue).unwrap();
+    assert!(json.starts_with(r#"{"messages":[{"id":0,"possibilities":[{"categories""#));
+    assert!(json.contains("fearPropension"));
+    assert!(bake(&database, &person, "tavern", 4, 2).messages.is_empty());
+
+    let mut database = database;
+    assert!(database.set_condition("", "Good harvest?", "job == Farmer"));
+    let dialogue = bake(&database, &person, "", 1, 2);
+    assert_eq!(dialogue.messages[0].possibilities.len(), 1);
+    assert_eq!(dialogue.messages[0].possibilities[0].contents, "Bless you.");
+}
+
+#[test]
+fn test_cycles() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    for text in ["How are you?", "Fine, and you?", "How are you?", "Bye."] {
+        chat.add_phrase(text);
+    }
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    for text in ["Again?", "Again?"] {
+        chat.add_phrase(text);
+    }
+
+    let index = |text: &str| database.get_index(text).unwrap();
+    let mut loop_indices = vec![index("How are you?"), index("Fine, and you?")];
+    loop_indices.sort();
+    assert_eq!(cycles(&database), vec![loop_indices, vec![index("Again?")]]);
+}
+
+#[test]
+fn test_preceding() {
+    let mut rng = ChaCha8Rng::seed_from_u64(71);
+    let person = generate_person(&mut rng);
+    let mut database = Database::new();
+    database.updated(SERVER);
+
+    for opener in ["Taxes again?", "How is the harvest?", "Taxes again?"] {
+        let mut chat = Chat::new(&mut database, true, &person);
+        chat.start("");
+        chat.add_phrase(opener);
+        chat.add_phrase("I hate taxes.");
+    }
+    let mut chat = Chat::new(&mut database, true, &person);
+    chat.start("");
+    chat.add_phrase("How is the harvest?");
+    chat.add_phrase("I hate taxes.");
+    assert!(chat.undo());
+
+    let index = |text: &str| database.get_index(text).unwrap();
+    let preceding: Vec<(usize, usize)> = database
+        .preceding(index("I hate taxes."))
+        .iter()
+        .map(|(&previous, &count)| (previous, count))
+        .collect();
+    assert_eq!(
+        preceding,
+        vec![(index("Taxes again?"), 2), (index("How is the harvest?"), 1)]
+    );
+    assert_preceding_indexed(&database);
+
+    let difference = database.difference(SERVER);
+    let taxes = difference.get_index("I hate taxes.").unwrap();
+    assert_eq!(difference.preceding(taxes).values().sum::<usize>(), 3);
+}
+
+#[test]
+fn test_report() {
+    let mut database = village_database();
+    database.insert_texts_at("Lonely.", vec!["Lonely.".to_string()]);
+    let mut response = database.phrases[0].responses[0].clone();
+    response.index = 99;
+    database.phrases[0].responses.push(response);
+
+    let report: serde_json::Value = serde_json::from_str(&report(&database).to_json()).unwrap();
+    assert_eq!(report["unreachable"], serde_json::json!(["Lonely."]));
+    assert_eq!(report["dead_ends"], serde_json::json!(["Go in peace.", "Lonely."]));
+    assert_eq!(report["dangling"], serde_json::json!([["", 99]]));
+    assert_eq!(report["coverage"]["Priest"], 4);
+    assert_eq!(report["coverage"]["Farmer"], 3);
+    assert_eq!(
+        report["uncovered_jobs"],
+        serde_json::json!(["Fisherman", "Miner", "Merchant", "Politician", "Noble"])
+    );
+    assert_eq!(report["cycles"].as_array().unwrap().len(), 1);
+
+    database.phrases.push(Phrase::new());
+    let json = crate::graph::report(&database).to_json();
+    let empty: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(empty["unreachable"], serde_json::json!(["Lonely.", "<empty>"]));
+    assert_eq!(empty["dead_ends"], serde_json::json!(["Go in peace.", "Lonely.", "<empty>"]));
+}
+
+#[test]
+fn test_validate() {
+    let database = village_database();
+    assert!(database.validate().is_valid());
+
+    let mut json: serde_json::Value = serde_json::from_str(&database.to_string()).unwrap();
+    json["size"] = serde_json::json!(1);
+    json["phrases"][1]["responses"][0]["index"] = serde_json::json!(99);
+    json["phrases"][2]["texts"] = serde_json::json!([]);
+    json["phrase_indices"]["lonely"] = serde_json::json!(42);
+    let mut broken = Database::from_str(&json.to_string()).unwrap();
+
+    let report: serde_json::Value = serde_json::from_str(&broken.validate().to_json()).unwrap();
+    let mut problems: Vec<&str> = report["problems"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|problem| problem["problem"].as_str().unwrap())
+        .collect();
+    problems.sort();
+    assert_eq!(
+        problems,
+        vec![
+            "dangling_response",
+            "empty_phrase",
+            "index_mismatch",
+            "index_out_of_range",
+            "stray_annotation",
+            "stray_annotation",
+            "wrong_size"
+        ]
+    );
+
+    // indices past the phrases are reported instead of indexed
+    let mut huge = json.clone();
+    huge["phrases"][1]["responses"][0]["index"] = serde_json::json!(u64::MAX);
+    let huge = Database::from_str(&huge.to_string()).unwrap();
+    assert!(!huge.validate().is_valid());
+
+    // the derived fields are rebuilt however the database is deserialized
+    let mut loaded: Database = serde_json::from_str(&database.to_string()).unwrap();
+    assert_preceding_indexed(&loaded);
+    let mut chat = Chat::new(&mut loaded, true, PRIEST);
+    chat.start("");
+    chat.add_phrase("Good harvest?");
+
+    let remaining = broken.repair();
+    assert_eq!(remaining.to_json(), r#"{"problems":[{"problem":"empty_phrase","index":2}]}"#);
+    assert_eq!(broken.size(), database.size() - 1);
+    assert_preceding_indexed(&broken);
+    assert!(database.needs_resync(&broken));
+
+    let repaired = Database::from_str(&broken.to_string()).unwrap();
+    broken.repair();
+    assert!(!repaired.needs_resync(&broken));
+}
+
+#[test]
+fn test_compact() {
+    let mut server = village_database();
+    server.insert_texts_at("Lonely.", vec!["Lonely.".to_string()]);
+    let mut chat = Chat::new(&mut server, true, PRIEST);
+    chat.start("");
+    chat.add_phrase("bless you");
+    assert!(server.set_condition("", "Good harvest?", "flag day"));
+    assert!(server.set_condition("", "Good harvest?", ""));
+    let phrases = server.phrases.len();
+    let size = server.size();
+
+    let mut client = Database::new();
+    client.updated(SERVER);
+    client.merge(server.total_clone());
+    client.updated(SERVER);
+    server.updated("client");
+
+    assert_eq!(server.compact(), 1);
+    assert!(server.validate().is_valid());
+    assert_eq!(server.phrases.len(), phrases - 1);
+    assert_eq!(server.size(), size);
+    assert!(server.get_index("Lonely.").is_none());
+    let blessing = &server.phrases[server.get_index("Bless you.").unwrap()];
+    assert_eq!(blessing.texts, vec!["Bless you.", "bless you"]);
+    assert!(server.phrases[0].conditions.is_empty());
+
+    let mut chat = Chat::new(&mut client, false, PRIEST);
+    chat.start("");
+    chat.add_phrase("Morning.");
+    let difference = server.difference("client");
+    assert!(client.needs_resync(&difference));
+    server.merge(client.difference(SERVER));
+    client.updated(SERVER);
+    chat.add_phrase("Fine day.");
+    client.resync(server.total_clone());
+    assert!(!client.needs_resync(&server.difference("client")));
+    assert!(client.get_index("Lonely.").is_none());
+
+    server.merge(client.difference(SERVER));
+    client.updated(SERVER);
+    server.updated("client");
+    assert_eq!(client, server);
+    assert_eq!(server.size(), size + 2);
+
+    // a phrase with the cloud of another one is merged into the indexed one
+    let blessing = server.get_index("Bless you.").unwrap();
+    let start = server.get_start_index().unwrap();
+    let mut json: serde_json::Value = serde_json::from_str(&server.to_string()).unwrap();
+    let mut duplicate = json["phrases"][blessing].clone();
+    duplicate["texts"] = serde_json::json!(["Bless you!"]);
+    let mut response = json["phrases"][start]["responses"][0].clone();
+    response["index"] = serde_json::json!(server.phrases.len());
+    json["phrases"].as_array_mut().unwrap().push(duplicate);
+    json["phrases"][start]["responses"].as_array_mut().unwrap().push(response);
+    let mut duplicated = Database::from_str(&json.to_string()).unwrap();
+    assert!(!duplicated.validate().is_valid());
+
+    let size: usize = duplicated.phrases.iter().map(Phrase::response_count).sum();
+    assert_eq!(duplicated.compact(), 1);
+    assert!(duplicated.validate().is_valid());
+    assert_eq!(duplicated.size(), size);
+    let blessing = &duplicated.phrases[duplicated.get_index("Bless you.").unwrap()];
+    assert_eq!(blessing.texts, vec!["Bless you.", "bless you", "Bless you!"]);
+    assert_preceding_indexed(&duplicated);
+}
+
+#[test]
+fn test_merge_removed_phrase() {
+    let mut server = village_database();
+    server.insert_texts_at("Lonely.", vec!["Lonely.".to_string()]);
+    let mut client = Database::new();
+    client.updated(SERVER);
+    client.merge(server.total_clone());
+    client.updated(SERVER);
+    assert_eq!(server.compact(), 1);
+
+    let person = GeneralPerson::new(serde_json::from_str(PRIEST).unwrap(), true);
+    let lonely = client.get_index("Lonely.").unwrap();
+    let start = client.get_start_index().unwrap();
+    let response = Response::new(lonely, person, Situation::default());
+    client.insert_responses_to(start, [response]);
+    let size = server.size();
+    server.merge(client.difference(SERVER));
+    assert!(server.get_index("Lonely.").is_none());
+    assert_eq!(server.size(), size);
+    assert!(server.validate().is_valid());
+}
+
+#[test]
+fn test_compact_empty_phrase() {
+    let database = village_database();
+    let thanks = database.get_index("Thank you, father.").unwrap();
+    let mut json: serde_json::Value = serde_json::from_str(&database.to_string()).unwrap();
+    json["phrases"][thanks]["texts"] = serde_json::json!([]);
+    let mut emptied = Database::from_str(&json.to_string()).unwrap();
+
+    assert_eq!(emptied.compact(), 1);
+    assert!(emptied.validate().is_valid());
+    let blessing = &emptied.phrases[emptied.get_index("Bless you.").unwrap()];
+    assert!(blessing.responses.is_empty());
+    assert_preceding_indexed(&emptied);
+}
+
+#[test]
+fn test_aggregated_responses() {
+    let mut server = Database::new();
+    let mut client = Database::new();
+    client.updated(SERVER);
+    for _ in 0..2 {
+        let mut chat = Chat::new(&mut client, true, PRIEST);
+        chat.start("");
+        chat.add_phrase("Bless you.");
+    }
+    assert_eq!(client.phrases[0].responses.len(), 1);
+    assert_eq!(client.phrases[0].responses[0].count, 2);
+    assert_eq!(client.size(), 2);
+    server.merge(client.difference(SERVER));
+    client.updated(SERVER);
+    server.updated("client");
+
+    let mut chat = Chat::new(&mut client, true, PRIEST);
+    chat.start("");
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    assert!(chat.undo());
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    let difference = client.difference(SERVER);
+    assert_eq!(difference.phrases[0].responses[0].count, 1);
+    server.merge(difference);
+    client.updated(SERVER);
+    assert_eq!(client, server);
+    let start = server.get_start_index().unwrap();
+    assert_eq!(server.phrases[start].responses[0].count, 3);
+    assert_eq!(server.difference("client").phrases[0].responses[0].count, 1);
+    let mut chat = Chat::new(&mut client, true, PRIEST);
+    chat.start("");
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    client.updated(SERVER);
+    assert!(!chat.undo());
+
+    // every choice used to be stored on its own, with situations kept aside
+    let mut json: serde_json::Value = serde_json::from_str(&server.to_string()).unwrap();
+    let response = json["phrases"][start]["responses"][0].clone();
+    let single = serde_json::json!([response["index"], response["person"]]);
+    json["phrases"][start]["responses"] = serde_json::json!([single, single, single]);
+    json["phrases"][start]["situations"] = serde_json::json!([[1, {"location": "chapel"}]]);
+    let mut legacy = Database::from_str(&json.to_string()).unwrap();
+    assert_eq!(legacy.phrases[start].responses.len(), 3);
+    assert_eq!(legacy.phrases[start].responses[1].situation.location.as_deref(), Some("chapel"));
+
+    assert_eq!(legacy.migrate(), 1);
+    assert!(server.needs_resync(&legacy));
+    let counts: Vec<u32> = legacy.phrases[start]
+        .responses
+        .iter()
+        .map(|response| response.count)
+        .collect();
+    assert_eq!(counts, vec![2, 1]);
+    assert_eq!(legacy.phrases[start].responses[0].traits, [2; 6]);
+    assert_eq!(legacy.size(), 3);
+    assert!(legacy.validate().is_valid());
+    assert_eq!(legacy.migrate(), 0);
+}
+
+#[test]
+fn test_aggregated_characters() {
+    let stern = r#"{"job": "Priest", "character": {"rebellion": 5, "fear_propension": 5, "popularity": 5, "animosity": 5, "political_agreement": 5, "fear": 5}}"#;
+    let mut server = Database::new();
+    let mut client = Database::new();
+    client.updated(SERVER);
+    let mut chat = Chat::new(&mut client, true, PRIEST);
+    chat.start("");
+    chat.add_phrase("Bless you.");
+    server.merge(client.difference(SERVER));
+    client.updated(SERVER);
+    server.updated("client");
+
+    let mut chat = Chat::new(&mut client, true, stern);
+    chat.start("");
+    chat.add_phrase("Bless you.");
+    let blessing = &client.phrases[0].responses;
+    assert_eq!(blessing.len(), 1);
+    assert_eq!(blessing[0].count, 2);
+    assert_eq!(blessing[0].traits, [6; 6]);
+    assert_eq!(blessing[0].person.trait_value(Trait::Rebellion), 3);
+
+    let mut chat = Chat::new(&mut client, true, stern);
+    chat.start("");
+    chat.get_phrases();
+    chat.choose_phrase(0);
+    assert!(chat.undo());
+    assert_eq!(client.phrases[0].responses[0].traits, [6; 6]);
+
+    let difference = client.difference(SERVER);
+    assert_eq!(difference.phrases[0].responses[0].traits, [5; 6]);
+    server.merge(difference);
+    client.updated(SERVER);
+    assert_eq!(client, server);
+    let start = server.get_start_index().unwrap();
+    assert_eq!(server.phrases[start].responses[0].traits, [6; 6]);
+
+    // history responses are aggregated and synced the same way
+    for person in [PRIEST, stern] {
+        let mut chat = Chat::new(&mut client, true, person);
+        chat.start("");
+        chat.set_history_length(2);
+        chat.add_phrase("Bless you.");
+        chat.add_phrase("Thank you.");
+        chat.add_phrase("Go in peace.");
+        assert!(chat.undo());
+        chat.add_phrase("Go in peace.");
+        server.merge(client.difference(SERVER));
+        client.updated(SERVER);
+    }
+    assert_eq!(client, server);
+    let thanks = &server.phrases[server.get_index("Thank you.").unwrap()];
+    assert_eq!(thanks.history_responses.len(), 1);
+    assert_eq!(thanks.history_responses[0].count, 2);
+    assert_eq!(thanks.history_responses[0].traits, [6; 6]);
+}
@@ -0,0 +1,82 @@
+use std::fmt::Write;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::data::GeneralPerson;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TurnKind {
+    Chosen,
+    Typed,
+    Ended,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Turn {
+    pub(crate) index: usize,
+    pub(crate) text: String,
+    // text as stored in the database when it had placeholders filled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) template: Option<String>,
+    pub(crate) youtalk: bool,
+    // participant name in multi-party conversations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) speaker: Option<String>,
+    pub(crate) kind: TurnKind,
+    // whether the turn was stored as a response in the database
+    pub(crate) recorded: bool,
+    // contributor who typed the chosen text variant, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) author: Option<GeneralPerson>,
+    // npc personas before the effects of the phrase changed them,
+    // a single persona in a two-person chat and every participant in a party
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) before_effects: Option<Vec<GeneralPerson>>,
+}
+
+impl Turn {
+    pub(crate) fn stored_text(&self) -> &str {
+        self.template.as_deref().unwrap_or(&self.text)
+    }
+}
+
+// ordered record of everything said during a Chat
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub(crate) struct Transcript {
+    pub(crate) turns: Vec<Turn>,
+}
+
+impl Transcript {
+    pub(crate) fn new() -> Self {
+        Transcript { turns: Vec::new() }
+    }
+
+    pub(crate) fn push(&mut self, turn: Turn) {
+        self.turns.push(turn);
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(&self.turns).unwrap_or_default()
+    }
+
+    pub(crate) fn to_markdown(&self) -> String {
+        let mut markdown = String::new();
+
+        for turn in &self.turns {
+            let speaker = match &turn.speaker {
+                Some(speaker) => speaker.as_str(),
+                None if turn.youtalk => "You",
+                None => "NPC",
+            };
+            let _ = write!(markdown, "- **{}:** {}", speaker, turn.text);
+            match turn.kind {
+                TurnKind::Chosen => {}
+                TurnKind::Typed => markdown += " _(typed)_",
+                TurnKind::Ended => markdown += " _(ended)_",
+            }
+            markdown += "\n";
+        }
+
+        markdown
+    }
+}
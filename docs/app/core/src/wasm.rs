@@ -1,7 +1,11 @@
 use wasm_bindgen::prelude::*;
 
-use crate::chat::Chat;
+use crate::bake::bake_all;
+use crate::chat::{Chat, Suggestion};
+use crate::data::GeneralPerson;
 use crate::database::{Database, SERVER};
+use crate::graph::{cycles, report};
+use crate::paths::best_paths;
 
 // light-weight wrapper around crate::database/chat for direct wasm use
 
@@ -22,32 +26,147 @@ pub struct ClientDatabase(Database);
 
 #[wasm_bindgen]
 impl ClientDatabase {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let mut database = Database::new();
         database.updated(SERVER);
         ClientDatabase(database)
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<ClientDatabase> {
-        Database::from_str(s).map(|x| ClientDatabase(x))
+        Database::from_str(s).map(ClientDatabase)
     }
 
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(&self) -> String {
         self.0.to_string()
     }
 
+    // json report of the broken invariants of the database
+    pub fn validate(&self) -> String {
+        self.0.validate().to_json()
+    }
+
+    // fixes what can be fixed, returns the report of the remaining problems
+    pub fn repair(&mut self) -> String {
+        self.0.repair().to_json()
+    }
+
     pub fn size(&self) -> usize {
         self.0.size()
     }
 
-    pub fn merge(&mut self, database: ClientDatabase) {
-        self.0.merge(database.0);
+    // fails when the server was compacted, the total database
+    // has to be fetched again and passed to resync
+    pub fn merge(&mut self, database: ClientDatabase) -> bool {
+        let merged = !self.0.needs_resync(&database.0);
+        if merged {
+            self.0.merge(database.0);
+        }
+        // the server got local changes with the request for the difference
         self.0.updated(SERVER);
+        merged
+    }
+
+    pub fn resync(&mut self, database: ClientDatabase) {
+        self.0.resync(database.0);
     }
 
     pub fn difference(&mut self) -> ClientDatabase {
         ClientDatabase(self.0.difference(SERVER))
     }
+
+    pub fn mark_ending(&mut self, text: &str, outcome: &str) -> bool {
+        self.0.mark_ending(text, outcome)
+    }
+
+    pub fn mark_generic(&mut self, text: &str) -> bool {
+        self.0.mark_generic(text)
+    }
+
+    pub fn set_condition(&mut self, text: &str, response_text: &str, condition: &str) -> bool {
+        self.0.set_condition(text, response_text, condition)
+    }
+
+    // json array of the count most probable conversations of at most length turns,
+    // each one as {"probability", "transcript"}
+    pub fn best_paths(
+        &self,
+        person_description: &str,
+        you_talk: bool,
+        context: &str,
+        length: usize,
+        count: usize,
+    ) -> Option<String> {
+        let person = GeneralPerson::new(serde_json::from_str(person_description).ok()?, you_talk);
+        serde_json::to_string(&best_paths(&self.0, &person, context, length, count)).ok()
+    }
+
+    // json array of {"text", "count"} objects for the phrases the given one
+    // was said in response to
+    pub fn preceding(&self, text: &str) -> Option<String> {
+        #[derive(serde_derive::Serialize)]
+        struct Preceding<'a> {
+            text: &'a str,
+            count: usize,
+        }
+
+        let index = self.0.get_index(text)?;
+        let preceding: Vec<Preceding> = self
+            .0
+            .preceding(index)
+            .iter()
+            .map(|(&previous, &count)| Preceding {
+                text: &self.0.phrases[previous].texts[0],
+                count,
+            })
+            .collect();
+        serde_json::to_string(&preceding).ok()
+    }
+
+    // json object with unreachable phrases, dead ends, dangling responses,
+    // answered phrases per job and cycles
+    pub fn report(&self) -> String {
+        report(&self.0).to_json()
+    }
+
+    // json array of the groups of phrases that can follow each other in a loop,
+    // each one as an array of phrase texts
+    pub fn cycles(&self) -> String {
+        let cycles: Vec<Vec<&str>> = cycles(&self.0)
+            .into_iter()
+            .map(|component| {
+                component
+                    .into_iter()
+                    .map(|index| self.0.phrases[index].texts[0].as_str())
+                    .collect()
+            })
+            .collect();
+        serde_json::to_string(&cycles).unwrap_or_default()
+    }
+
+    // json array with a dialogue tree in the chatDB format for every npc
+    // of the json array of person descriptions
+    pub fn bake(
+        &self,
+        persons_description: &str,
+        you_talk: bool,
+        context: &str,
+        depth: usize,
+        breadth: usize,
+    ) -> Option<String> {
+        bake_all(&self.0, persons_description, you_talk, context, depth, breadth)
+    }
+
+    // effects are passed as a json array, e.g. [{"trait": "fear", "delta": 2}]
+    pub fn set_effects(&mut self, text: &str, effects: &str) -> bool {
+        if let Ok(effects) = serde_json::from_str(effects) {
+            self.0.set_effects(text, effects)
+        } else {
+            false
+        }
+    }
 }
 
 #[wasm_bindgen]
@@ -59,14 +178,98 @@ impl ClientChat {
         ClientChat(Chat::new(&mut database.0, you_talk, person_description))
     }
 
-    pub fn start(&mut self) {
-        self.0.start();
+    pub fn new_party(database: &mut ClientDatabase, participants: &str) -> Option<ClientChat> {
+        Chat::new_party(&mut database.0, participants).map(ClientChat)
+    }
+
+    pub fn restore(database: &mut ClientDatabase, state: &str) -> Option<ClientChat> {
+        let state = serde_json::from_str(state).ok()?;
+        Chat::restore(&mut database.0, state).map(ClientChat)
+    }
+
+    pub fn start(&mut self, context: &str) {
+        self.0.start(context);
+    }
+
+    pub fn set_flag(&mut self, flag: &str, value: bool) {
+        self.0.set_flag(flag, value);
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: &str) {
+        self.0.set_variable(name, value);
+    }
+
+    pub fn set_situation(&mut self, situation_description: &str) -> bool {
+        self.0.set_situation(situation_description)
+    }
+
+    pub fn set_situation_filter(&mut self, strict: bool) {
+        self.0.set_situation_filter(strict);
+    }
+
+    pub fn choose_speaker(&mut self, name: &str) -> bool {
+        self.0.choose_speaker(name)
+    }
+
+    pub fn person_description(&self) -> String {
+        self.0.person_description()
+    }
+
+    pub fn participants_description(&self) -> String {
+        self.0.participants_description()
+    }
+
+    pub fn save_state(&mut self) -> String {
+        serde_json::to_string(&self.0.state()).unwrap_or_default()
+    }
+
+    // goals are passed as a json array of phrase texts
+    pub fn set_goals(&mut self, goals_description: &str) -> bool {
+        self.0.set_goals(goals_description)
+    }
+
+    pub fn set_repetition_penalty(&mut self, penalty: f32, window: usize) {
+        self.0.set_repetition_penalty(penalty, window);
+    }
+
+    pub fn set_goal_strength(&mut self, strength: f32) {
+        self.0.set_goal_strength(strength);
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.0.set_seed(seed);
+    }
+
+    pub fn set_voice_strength(&mut self, strength: f32) {
+        self.0.set_voice_strength(strength);
+    }
+
+    pub fn set_history_length(&mut self, length: usize) {
+        self.0.set_history_length(length);
+    }
+
+    // fallbacks are passed as a json array, e.g. ["Similar", "Generic"]
+    pub fn set_fallbacks(&mut self, fallbacks: &str) -> bool {
+        if let Ok(fallbacks) = serde_json::from_str(fallbacks) {
+            self.0.set_fallbacks(fallbacks);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn fallback(&self) -> Option<String> {
+        self.0.fallback().map(|fallback| format!("{:?}", fallback))
     }
 
     pub fn get_phrases(&mut self) -> Box<[JsValue]> {
         iter_to_jsarray(self.0.get_phrases().iter())
     }
 
+    pub fn get_suggestions(&mut self) -> Box<[JsValue]> {
+        iter_to_jsarray(self.0.get_suggestions().into_iter().map(ClientSuggestion))
+    }
+
     pub fn add_phrase(&mut self, text: &str) {
         self.0.add_phrase(text);
     }
@@ -78,4 +281,66 @@ impl ClientChat {
     pub fn choose_phrase_immutably(&mut self, option_number: usize) {
         self.0.choose_phrase_immutably(option_number);
     }
+
+    pub fn end_conversation(&mut self, outcome: &str) {
+        self.0.end_conversation(outcome);
+    }
+
+    pub fn outcome(&mut self) -> Option<String> {
+        self.0.outcome()
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.0.undo()
+    }
+
+    pub fn transcript_json(&self) -> String {
+        self.0.transcript().to_json()
+    }
+
+    pub fn transcript_markdown(&self) -> String {
+        self.0.transcript().to_markdown()
+    }
+}
+
+#[wasm_bindgen]
+pub struct ClientSuggestion(Suggestion);
+
+#[wasm_bindgen]
+impl ClientSuggestion {
+    #[wasm_bindgen(getter)]
+    pub fn index(&self) -> usize {
+        self.0.index
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn text(&self) -> String {
+        self.0.text.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn probability(&self) -> f32 {
+        self.0.probability
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn contributors(&self) -> usize {
+        self.0.contributors
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ending(&self) -> Option<String> {
+        self.0.ending.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn fallback(&self) -> Option<String> {
+        self.0.fallback.map(|fallback| format!("{:?}", fallback))
+    }
+
+    // personas are returned as a json array, same format as person descriptions
+    #[wasm_bindgen(getter)]
+    pub fn personas(&self) -> String {
+        serde_json::to_string(&self.0.personas).unwrap_or_default()
+    }
 }
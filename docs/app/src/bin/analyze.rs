@@ -0,0 +1,23 @@
+use std::{env, fs, process};
+
+use looped::database::Database;
+use looped::graph::report;
+
+// prints a json report of unreachable phrases, dead ends, dangling responses,
+// job coverage and cycles of the database
+fn main() {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: analyze <database.json>");
+        process::exit(2);
+    };
+
+    let database = fs::read_to_string(&path)
+        .ok()
+        .and_then(|text| Database::from_str(&text))
+        .unwrap_or_else(|| {
+            eprintln!("cannot read database from {}", path);
+            process::exit(1);
+        });
+
+    println!("{}", report(&database).to_json());
+}
@@ -0,0 +1,48 @@
+use std::{env, fs, process};
+
+use looped::bake::bake_all;
+use looped::database::Database;
+
+const USAGE: &str = "usage: bake <database.json> <persons.json> [context] [depth] [breadth] \
+                     [you talk]";
+
+// prints a json array with the dialogue tree in the chatDB format of every npc
+// described in the persons file, for game builds without the server
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let number = |position: usize, default: usize| {
+        args.get(position).map_or(default, |arg| {
+            arg.parse().unwrap_or_else(|_| {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            })
+        })
+    };
+    let context = args.get(2).map_or("", String::as_str);
+    let depth = number(3, 6);
+    let breadth = number(4, 3);
+    let you_talk = args.get(5).is_none_or(|arg| arg != "false");
+
+    let database = fs::read_to_string(&args[0])
+        .ok()
+        .and_then(|text| Database::from_str(&text))
+        .unwrap_or_else(|| {
+            eprintln!("cannot read database from {}", args[0]);
+            process::exit(1);
+        });
+    let persons = fs::read_to_string(&args[1]).unwrap_or_else(|_| {
+        eprintln!("cannot read persons from {}", args[1]);
+        process::exit(1);
+    });
+
+    let Some(dialogues) = bake_all(&database, &persons, you_talk, context, depth, breadth) else {
+        eprintln!("cannot parse person descriptions");
+        process::exit(2);
+    };
+    println!("{}", dialogues);
+}
@@ -0,0 +1,62 @@
+use std::io::{self, BufWriter, Write};
+use std::{env, fs, process};
+
+use looped::database::Database;
+use looped::simulation::{simulate, SimulationStats};
+
+const USAGE: &str = "usage: simulate <database.json> <first person json> <second person json> \
+                     [conversations] [seed] [max turns] [context]";
+
+// writes simulated conversations as json lines to stdout and their statistics to stderr
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 3 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let number = |position: usize, default: u64| {
+        args.get(position).map_or(default, |arg| {
+            arg.parse().unwrap_or_else(|_| {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            })
+        })
+    };
+    let conversations = number(3, 100);
+    let seed = number(4, 0);
+    let max_turns = number(5, 50) as usize;
+    let context = args.get(6).map_or("", String::as_str);
+
+    let mut database = fs::read_to_string(&args[0])
+        .ok()
+        .and_then(|text| Database::from_str(&text))
+        .unwrap_or_else(|| {
+            eprintln!("cannot read database from {}", args[0]);
+            process::exit(1);
+        });
+
+    let mut stats = SimulationStats::new();
+    let mut output = BufWriter::new(io::stdout().lock());
+    for conversation in 0..conversations {
+        let Some(simulation) = simulate(
+            &mut database,
+            &args[1],
+            &args[2],
+            context,
+            max_turns,
+            seed.wrapping_add(conversation),
+        ) else {
+            eprintln!("cannot parse person descriptions");
+            process::exit(2);
+        };
+
+        stats.add(&simulation);
+        if writeln!(output, "{}", simulation.to_json()).is_err() {
+            process::exit(1);
+        }
+    }
+
+    drop(output);
+    eprintln!("{}", stats.to_json());
+}
@@ -14,7 +14,10 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use rustls::ServerConfig;
 use log::{info, warn};
 
-use core::database::Database;
+use looped::database::Database;
+
+// where the database is loaded from at startup and saved to after compaction
+const DATABASE_PATH: &str = "database.json";
 
 fn enable_cors(response: &mut Response<Body>) {
     let headers = response.headers_mut();
@@ -27,6 +30,13 @@ fn enable_cors(response: &mut Response<Body>) {
     headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("300"));
 }
 
+fn is_local(address: &str) -> bool {
+    address
+        .parse::<SocketAddr>()
+        .map(|address| address.ip().is_loopback())
+        .unwrap_or(false)
+}
+
 fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
     let certfile = fs::File::open(filename)?;
     let mut reader = io::BufReader::new(certfile);
@@ -161,7 +171,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     env_logger::init();
 
     let addr = ([0, 0, 0, 0], 3000).into();
-    let database = Arc::new(Mutex::new(Database::new()));
+    let mut database: Database = fs::read_to_string(DATABASE_PATH)
+        .ok()
+        .and_then(|text| Database::from_str(&text))
+        .unwrap_or_default();
+    let merged = database.migrate();
+    if merged > 0 {
+        info!("merged {} responses of the loaded database", merged);
+        if let Err(err) = fs::write(DATABASE_PATH, database.to_string()) {
+            warn!("migrated database wasn't saved: {}", err);
+        }
+    }
+    let database = Arc::new(Mutex::new(database));
 
     let tls_cfg = {
         let certs = load_certs("certificate.crt")?;
@@ -200,8 +221,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                             let mut dat = database.lock().unwrap();
                             let difference = dat.difference(&address);
 
+                            // phrase indices of clients that missed a compaction do not
+                            // match, they resync and send their changes again
                             if let Some(got_database) = Database::from_slice(&bytes) {
-                                dat.merge(got_database);
+                                if dat.needs_resync(&got_database) {
+                                    warn!("outdated database difference from {} wasn't merged", address);
+                                } else {
+                                    dat.merge(got_database);
+                                }
                             } else {
                                 warn!("database difference from {} wasn't merged", address);
                             }
@@ -214,6 +241,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                             enable_cors(&mut response);
                             Ok::<_, hyper::Error>(response)
                         }
+                        // only the machine of the server may compact, clients resync
+                        // with the next generation on their next update
+                        (&Method::POST, "/compact") if is_local(&address) => {
+                            let mut dat = database.lock().unwrap();
+                            let removed = dat.compact();
+                            if let Err(err) = fs::write(DATABASE_PATH, dat.to_string()) {
+                                warn!("compacted database wasn't saved: {}", err);
+                            }
+                            info!("compacted database, removed {} phrases", removed);
+
+                            Ok::<_, hyper::Error>(Response::new(Body::from(removed.to_string())))
+                        }
                         (&Method::OPTIONS, _) => {
                             let mut response = Response::default();
                             enable_cors(&mut response);
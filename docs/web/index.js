@@ -54,7 +54,7 @@ function initialize() {
             generateButton.style.visibility = "hidden";
 
             chat = ClientChat.new(database, youTalk, serializePerson());
-            chat.start();
+            chat.start("");
 
             postPhrases();
         } else {
@@ -156,7 +156,7 @@ function loadDatabase() {
             if (xmlHttp.status == 200) {
                 const server_database = ClientDatabase.from_str(xmlHttp.responseText);
                 if (server_database) {
-                    database.merge(server_database);
+                    database.resync(server_database);
                 }
                 online = true;
             }
@@ -172,14 +172,31 @@ function loadDatabase() {
 
 loadDatabase();
 
+// the server was compacted, phrases have to be fetched again
+function resyncDatabase() {
+    let xmlHttp = new XMLHttpRequest();
+    xmlHttp.onreadystatechange = () => {
+        if (xmlHttp.readyState == 4 && xmlHttp.status == 200) {
+            const server_database = ClientDatabase.from_str(xmlHttp.responseText);
+            if (server_database) {
+                database.resync(server_database);
+                databaseSize = database.size();
+                dataSize.textContent = databaseSize.toString();
+            }
+        }
+    };
+    xmlHttp.open("GET", serverURL + "/database", true);
+    xmlHttp.send();
+}
+
 function updateDatabase() {
     let xmlHttp = new XMLHttpRequest();
     xmlHttp.onreadystatechange = () => { 
         if (xmlHttp.readyState == 4) {
             if (xmlHttp.status == 200) {
                 const difference = ClientDatabase.from_str(xmlHttp.responseText);
-                if (difference) {
-                    database.merge(difference);
+                if (difference && !database.merge(difference)) {
+                    resyncDatabase();
                 }
                 online = true;
             } else {